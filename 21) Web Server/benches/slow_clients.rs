@@ -0,0 +1,102 @@
+/* Summary:
+Compares the two server modes when the clients are slow.
+
+$ cargo bench --bench slow_clients
+
+Each slow client trickles its request in one byte at a time. In thread-pool mode every one of
+them holds a worker until it's done, so with 4 workers they're served 4 at a time, and a fast
+request sent meanwhile waits behind them. In event mode the slow clients only cost a buffer each.
+*/
+
+use std::{
+    io::prelude::*,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::{
+    ThreadPool,
+    config::{Config, Mode},
+    event_loop::serve_events,
+    http::{Request, Response},
+    middleware::Handler,
+    server::{Shutdown, serve_threads},
+};
+
+const WORKERS: usize = 4;
+const SLOW_CLIENTS: usize = 16;
+const BYTE_DELAY: Duration = Duration::from_millis(20);
+
+fn main() {
+    println!("{SLOW_CLIENTS} slow clients, {WORKERS} workers, {BYTE_DELAY:?} between bytes\n");
+
+    for mode in [Mode::Threads, Mode::Events] {
+        let addr = start_server(mode);
+
+        let start = Instant::now();
+        let slow: Vec<_> = (0..SLOW_CLIENTS)
+            .map(|_| thread::spawn(move || slow_request(addr)))
+            .collect();
+
+        // Give the slow clients time to connect and grab the workers, then time a normal request.
+        thread::sleep(Duration::from_millis(100));
+        let fast_start = Instant::now();
+        fast_request(addr);
+        let fast = fast_start.elapsed();
+
+        for client in slow {
+            client.join().unwrap();
+        }
+        let total = start.elapsed();
+
+        println!("{mode:?}: fast request took {fast:.2?}, all slow clients done in {total:.2?}");
+    }
+}
+
+// Run a server in the background on an ephemeral port, and return its address.
+fn start_server(mode: Mode) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let app: Arc<dyn Handler> = Arc::new(|_: &Request| Response::new(200).with_body("hello"));
+    let config = Arc::new(Config {
+        mode,
+        ..Config::default()
+    });
+
+    // The server runs until the benchmark exits.
+    let shutdown = Shutdown::new(&listener).unwrap();
+
+    thread::spawn(move || {
+        let pool = ThreadPool::new(WORKERS);
+        match mode {
+            Mode::Threads => serve_threads(listener, &pool, app, config, shutdown),
+            Mode::Events => serve_events(listener, &pool, app, config, shutdown).unwrap(),
+        }
+    });
+
+    addr
+}
+
+fn slow_request(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for byte in b"GET / HTTP/1.1\r\nHost: x\r\n\r\n" {
+        stream.write_all(&[*byte]).unwrap();
+        thread::sleep(BYTE_DELAY);
+    }
+    read_response(stream);
+}
+
+fn fast_request(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    read_response(stream);
+}
+
+fn read_response(mut stream: TcpStream) {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+}
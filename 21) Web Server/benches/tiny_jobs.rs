@@ -0,0 +1,109 @@
+/* Summary:
+Compares the work-stealing ThreadPool with the single shared channel it replaced, when there are
+lots of tiny jobs.
+
+$ cargo bench --bench tiny_jobs
+
+Each job does next to nothing, so what's measured is the cost of handing jobs to workers.
+Each run ends by dropping the pool, which waits for every job to finish. The jobs are sent from
+one thread, like the server's accept loop, and then from several at once.
+*/
+
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::ThreadPool;
+
+const JOBS: usize = 1_000_000;
+const WORKERS: usize = 4;
+const SENDERS: usize = 4;
+
+fn main() {
+    println!("{JOBS} tiny jobs, {WORKERS} workers\n");
+
+    for senders in [1, SENDERS] {
+        let old = time(|| ChannelPool::new(WORKERS), |pool, job| pool.execute(job), senders);
+        let new = time(|| ThreadPool::new(WORKERS), |pool, job| pool.execute(job).unwrap(), senders);
+
+        println!("from {senders} thread(s):");
+        println!("  channel pool:       {old:>8.2?}");
+        println!("  work-stealing pool: {new:>8.2?}  ({:.1}x)\n", old.as_secs_f64() / new.as_secs_f64());
+    }
+}
+
+// Send JOBS jobs to a new pool, split between `senders` threads, and wait for them all to run.
+fn time<P: Sync>(new_pool: impl Fn() -> P, execute: impl Fn(&P, fn()) + Sync, senders: usize) -> Duration {
+    let pool = new_pool();
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        for _ in 0..senders {
+            scope.spawn(|| {
+                for _ in 0..JOBS / senders {
+                    execute(&pool, tiny_job);
+                }
+            });
+        }
+    });
+    drop(pool);
+
+    start.elapsed()
+}
+
+fn tiny_job() {
+    black_box((0..10u64).sum::<u64>());
+}
+
+/* The pool as it was before: one channel, and every worker locks the same mutex to take a job.
+The "got a job" line it printed for each job is left out, or that's all this would measure.
+*/
+struct ChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+impl ChannelPool {
+    fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || {
+                    loop {
+                        let message = receiver.lock().unwrap().recv();
+                        match message {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        ChannelPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn execute(&self, f: impl FnOnce() + Send + 'static) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
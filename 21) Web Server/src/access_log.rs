@@ -0,0 +1,388 @@
+/* Summary:
+An access log middleware. Writes one line per request with the remote address, method, path,
+status, response size and how long the request took.
+
+Lines can be written in Common Log Format, Combined Log Format (Common plus Referer and
+User-Agent), or JSON. The duration is appended to the Common/Combined lines in microseconds,
+like Apache's %D.
+
+A streamed body's size isn't known until it's been sent, so its line is written then, with the
+bytes that actually went out and the time that took. The size is of the body as sent, so after
+compression, and without the headers or chunk framing.
+
+Responses the server sends without the app, like a 400 for a request it couldn't parse, a 503
+when the pool is full, or a 500 from a handler that panicked, are logged too. See unhandled().
+
+The log usually goes to a RotatingFile, which moves a full log aside (access.log -> access.log.1)
+and starts a new one, so the log never grows without limit.
+*/
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    date::DateTime,
+    http::{Request, Response, Stream},
+    middleware::{Middleware, Next, Unhandled},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+// Workers log from different threads, so writes to the shared output take turns.
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+pub struct AccessLog {
+    format: LogFormat,
+    output: Output,
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat, output: impl Write + Send + 'static) -> Self {
+        AccessLog {
+            format,
+            output: Arc::new(Mutex::new(Box::new(output))),
+        }
+    }
+
+    // Log to a file that is rotated once it reaches max_bytes, keeping `keep` old files.
+    pub fn to_file(
+        path: impl AsRef<Path>,
+        format: LogFormat,
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let file = RotatingFile::open(path, max_bytes, keep)?;
+        Ok(AccessLog::new(format, file))
+    }
+}
+
+/* The request is None when the server answered before it had one it could read, e.g. with a 400.
+Its request line is then logged as "-", like Apache does.
+*/
+fn format_line(
+    format: LogFormat,
+    remote_addr: Option<SocketAddr>,
+    request: Option<&Request>,
+    status: u16,
+    bytes: u64,
+    duration: Duration,
+) -> String {
+    let remote = match remote_addr {
+        Some(addr) => addr.ip().to_string(),
+        None => "-".to_string(),
+    };
+    let time = DateTime::now();
+    let request_line = match request {
+        Some(request) => format!("{} {} {}", request.method, request.path, request.version),
+        None => "-".to_string(),
+    };
+    let header = |name| request.and_then(|request| request.header(name));
+    let micros = duration.as_micros();
+
+    match format {
+        LogFormat::Common => format!(
+            "{remote} - - [{}] \"{}\" {status} {bytes} {micros}",
+            time.to_clf(),
+            escape(&request_line),
+        ),
+        LogFormat::Combined => format!(
+            "{remote} - - [{}] \"{}\" {status} {bytes} \"{}\" \"{}\" {micros}",
+            time.to_clf(),
+            escape(&request_line),
+            escape(header("Referer").unwrap_or("-")),
+            escape(header("User-Agent").unwrap_or("-")),
+        ),
+        LogFormat::Json => format!(
+            "{{\"time\":\"{}\",\"remote_addr\":\"{remote}\",\"method\":\"{}\",\"path\":\"{}\",\
+            \"status\":{status},\"bytes\":{bytes},\"duration_us\":{micros},\"request_id\":\"{}\"}}",
+            time.to_rfc3339(),
+            escape(request.map_or("", |request| request.method.as_str())),
+            escape(request.map_or("", |request| request.path.as_str())),
+            escape(header("X-Request-Id").unwrap_or("")),
+        ),
+    }
+}
+
+fn write_line(output: &Output, mut line: String) {
+    line.push('\n');
+
+    // The line goes out in a single write, so a rotation can't split it across two files.
+    // A failed log write shouldn't fail the request, so just report it.
+    let mut output = output.lock().unwrap();
+    if let Err(e) = output.write_all(line.as_bytes()).and_then(|_| output.flush()) {
+        eprintln!("Access log write failed: {e}");
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let start = Instant::now();
+        let mut response = next.run(request);
+
+        let Some(stream) = response.stream.take() else {
+            let bytes = response.body.len() as u64;
+            let line = format_line(self.format, request.remote_addr, Some(request), response.status, bytes, start.elapsed());
+            write_line(&self.output, line);
+            return response;
+        };
+
+        // The body isn't logged, so there's no need to keep a copy of it.
+        let sent = Sent {
+            format: self.format,
+            output: Arc::clone(&self.output),
+            request: Request {
+                method: request.method.clone(),
+                path: request.path.clone(),
+                version: request.version.clone(),
+                headers: request.headers.clone(),
+                body: Vec::new(),
+                remote_addr: request.remote_addr,
+            },
+            status: response.status,
+            bytes: 0,
+            start,
+        };
+        response.stream = Some(Stream::new(move |writer| {
+            let mut sent = sent; // All of it, not just a copy of `bytes`, so it's dropped in here.
+            stream.run(&mut Counted {
+                writer,
+                bytes: &mut sent.bytes,
+            })
+        }));
+
+        response
+    }
+
+    // The server answered by itself, so this is the only line there'll be for the request.
+    fn unhandled(&self, response: &Unhandled<'_>) {
+        let line = format_line(
+            self.format,
+            response.remote_addr,
+            response.request,
+            response.status,
+            response.bytes,
+            response.duration,
+        );
+        write_line(&self.output, line);
+    }
+}
+
+/* The log line for a streamed response, written once the stream has run and `bytes` is known.
+It's written on drop, so a stream that fails, or is never run at all, is still logged.
+*/
+struct Sent {
+    format: LogFormat,
+    output: Output,
+    request: Request,
+    status: u16,
+    bytes: u64,
+    start: Instant,
+}
+
+impl Drop for Sent {
+    fn drop(&mut self) {
+        let line = format_line(
+            self.format,
+            self.request.remote_addr,
+            Some(&self.request),
+            self.status,
+            self.bytes,
+            self.start.elapsed(),
+        );
+        write_line(&self.output, line);
+    }
+}
+
+// Passes writes on, counting the bytes that were taken.
+struct Counted<'a> {
+    writer: &'a mut dyn Write,
+    bytes: &'a mut u64,
+}
+
+impl Write for Counted<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        *self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/* Escape quotes, backslashes and control characters. The values come from the client,
+so without this a request could forge extra fields or whole extra lines in the log.
+This is valid for both the quoted CLF fields and JSON strings.
+*/
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/* A log file that rotates by size.
+When a write would take the file past max_bytes, access.log.1 becomes access.log.2 and so on,
+access.log becomes access.log.1, and a fresh access.log is started. Only `keep` old files are kept.
+*/
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len(); // Continue where the last run left off.
+
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?; // Nothing to keep, just truncate.
+        } else {
+            // Shift the old files up by one, from the oldest down. The oldest falls off the end.
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::{
+        middleware::{Handler, Pipeline},
+        server,
+    };
+
+    // An output the test can read back while the log holds on to it.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streamed_responses_log_the_bytes_sent() {
+        let lines = Lines::default();
+        let app = Pipeline::new(|_: &Request| Response::new(200).with_stream(|writer| writer.write_all(b"Hello, world")))
+            .with(AccessLog::new(LogFormat::Json, lines.clone()));
+
+        let mut response = app.handle(&mut Request::parse(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap());
+        assert!(lines.0.lock().unwrap().is_empty()); // Not until it's been sent
+        response.write_to(&mut Vec::new()).unwrap();
+
+        let logged = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        assert!(logged.contains("\"status\":200,\"bytes\":12,"), "{logged}");
+    }
+
+    #[test]
+    fn responses_the_server_makes_itself_are_logged() {
+        let lines = Lines::default();
+        let app = Pipeline::new(|_: &Request| -> Response { panic!("handler bug") })
+            .with(AccessLog::new(LogFormat::Common, lines.clone()));
+        let remote_addr = Some(SocketAddr::from(([127, 0, 0, 1], 4321)));
+
+        for raw in ["nonsense\r\n\r\n", "GET /boom HTTP/1.1\r\n\r\n"] {
+            let parsed = Request::parse(&mut raw.as_bytes());
+            server::respond(parsed, remote_addr, &app, Instant::now()).unwrap();
+        }
+
+        let logged = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        let logged: Vec<&str> = logged.lines().collect();
+        assert_eq!(logged.len(), 2, "{logged:?}");
+        assert!(logged[0].starts_with("127.0.0.1 - - [") && logged[0].contains("] \"-\" 400 "), "{logged:?}");
+        assert!(logged[1].contains("\"GET /boom HTTP/1.1\" 500 "), "{logged:?}");
+    }
+
+    #[test]
+    fn escapes_quotes_and_newlines() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\u000ad");
+    }
+
+    #[test]
+    fn rotating_file_keeps_limited_history() {
+        let dir = TempDir::new("access-log");
+        let path = dir.join("access.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        // Each line pushes the file past 10 bytes, so each one starts a new file.
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("access.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("access.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("access.log.3").exists());
+    }
+}
@@ -0,0 +1,159 @@
+/* Summary:
+The hello app itself: its routes, and the middleware every request goes through.
+
+It lives in the library rather than in main.rs so the tests can start it too. See testing.rs.
+*/
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    ThreadPool,
+    access_log::AccessLog,
+    compress::Compress,
+    config::Config,
+    http::{Request, Response},
+    metrics::Metrics,
+    middleware::{Cors, Handler, Pipeline, RequestId, Timing},
+    proxy::Proxy,
+    static_files::StaticFiles,
+    template::{Context, Templates},
+    vhost::VirtualHosts,
+    websocket::{self, WebSocket},
+};
+
+/* Build the app for the given config. Every request is written to the access log, and
+/metrics reports on the requests and on the pool that runs them.
+Each virtual host has the same routes, serving files from its own doc root, and the files in
+its static directory under /static. The proxies are shared by all of them.
+*/
+pub fn new(config: &Config, access_log: AccessLog, pool: &ThreadPool) -> Pipeline {
+    // Upstreams that are down get checked again every 10 seconds.
+    let proxies: Arc<[Proxy]> = config
+        .proxies
+        .iter()
+        .map(|(prefix, upstreams)| {
+            let upstreams: Vec<&str> = upstreams.iter().map(String::as_str).collect();
+            Proxy::new(prefix, &upstreams).health_check("/", Duration::from_secs(10))
+        })
+        .collect();
+
+    let routes = |doc_root: &Path| Routes {
+        doc_root: doc_root.to_path_buf(),
+        templates: Templates::new(doc_root).reload_on_change(config.dev_mode),
+        files: StaticFiles::new("/static", doc_root.join("static")),
+        proxies: Arc::clone(&proxies),
+    };
+
+    let mut sites = VirtualHosts::new(routes(&config.doc_root));
+    for (host, doc_root) in &config.vhosts {
+        sites = sites.host(host, routes(doc_root));
+    }
+
+    let mut metrics = Metrics::new().pool(pool.monitor());
+    for route in ["/", "/sleep", "/info", "/ws", "/static"] {
+        metrics = metrics.route(route);
+    }
+    for (prefix, _) in &config.proxies {
+        metrics = metrics.route(prefix);
+    }
+
+    // Every request goes through the middleware, in this order, before reaching the routes.
+    Pipeline::new(sites)
+        .with(access_log)
+        .with(metrics)
+        .with(RequestId::new())
+        .with(Timing)
+        .with(Cors::any())
+        .with(Compress::new())
+}
+
+struct Routes {
+    doc_root: PathBuf,
+    templates: Templates,
+    files: StaticFiles,
+    proxies: Arc<[Proxy]>,
+}
+
+impl Handler for Routes {
+    fn handle(&self, request: &mut Request) -> Response {
+        if let Some(proxy) = self.proxies.iter().find(|proxy| proxy.matches(request)) {
+            return proxy.handle(request);
+        }
+
+        if self.files.matches(request) {
+            return self.files.handle(request);
+        }
+
+        if request.path == "/ws" {
+            return websocket::upgrade(request, echo);
+        }
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => self.file(200, "hello.html"),
+            ("GET", "/sleep") => {
+                thread::sleep(Duration::from_secs(5));
+                self.file(200, "hello.html")
+            }
+            ("GET", "/info") => self.page(200, "info.html", &request_info(request)),
+            _ => self.page(404, "404.html", &Context::new().with("path", request.path.as_str())),
+        }
+    }
+}
+
+impl Routes {
+    // A static page.
+    fn file(&self, status: u16, filename: &str) -> Response {
+        match fs::read_to_string(self.doc_root.join(filename)) {
+            Ok(contents) => Response::new(status).with_body(contents),
+            Err(e) => {
+                eprintln!("Could not read {filename}: {e}");
+                Response::error(500)
+            }
+        }
+    }
+
+    // A page rendered from a template.
+    fn page(&self, status: u16, template: &str, context: &Context) -> Response {
+        match self.templates.render(template, context) {
+            Ok(html) => Response::new(status)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(html),
+            // An error page is still sent, only plainer, e.g. for a site without its own 404.html
+            Err(e) => {
+                eprintln!("Could not render {e}");
+                Response::error(if status >= 400 { status } else { 500 })
+            }
+        }
+    }
+}
+
+// What the /info page shows: the request, as the server saw it.
+fn request_info(request: &Request) -> Context {
+    let headers: Vec<_> = request
+        .headers
+        .iter()
+        .map(|(name, value)| Context::new().with("name", name.as_str()).with("value", value.as_str()))
+        .collect();
+
+    Context::new()
+        .with("method", request.method.as_str())
+        .with("path", request.path.as_str())
+        .with("version", request.version.as_str())
+        .with("remote_addr", request.remote_addr.map(|addr| addr.to_string()))
+        .with("headers", headers)
+}
+
+// A WebSocket demo: sends back every message it gets.
+fn echo(ws: WebSocket) {
+    while let Some(message) = ws.recv() {
+        if ws.send(message).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,261 @@
+/* Summary:
+Compresses response bodies with gzip or deflate, when the client says it can take them:
+
+    GET / HTTP/1.1
+    Accept-Encoding: gzip, deflate;q=0.5
+
+    HTTP/1.1 200 OK
+    Content-Encoding: gzip
+    Vary: Accept-Encoding
+
+Compress is a middleware, so handlers just produce plain bodies. A streamed body is compressed
+as it's streamed. Bodies that are tiny, or already compressed (images, zips, ...), are left alone,
+since compressing them costs time and saves nothing.
+
+Partial content (206) is left alone too: the ranges are of the file as it is, so compressing
+them would make a mess of a resumed download. So is a whole response that offers ranges
+(Accept-Ranges: bytes), like a static file's. A client that's sent part of it gzipped can't then
+ask for the rest by range, and the ETag an If-Range needs has to stay the file's own.
+*/
+
+use std::io::{self, prelude::*};
+
+use flate2::{
+    Compression,
+    write::{GzEncoder, ZlibEncoder},
+};
+
+use crate::{
+    http::{Request, Response, Stream},
+    middleware::{Middleware, Next},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate, // HTTP's "deflate" is really zlib: deflate data with a small header and a checksum.
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /* Pick an encoding from an Accept-Encoding header. Each coding may have a q value from 0 to 1
+    saying how much it's wanted, and q=0 means not at all. "*" stands for anything not listed.
+    The highest q wins, and gzip wins a tie. None means send the body as it is.
+    */
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut gzip = None;
+        let mut deflate = None;
+        let mut any = None;
+
+        for coding in accept_encoding.split(',') {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap().trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            match name.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(q),
+                "deflate" => deflate = Some(q),
+                "*" => any = Some(q),
+                _ => {}
+            }
+        }
+
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        let deflate = deflate.or(any).unwrap_or(0.0);
+
+        if gzip > 0.0 && gzip >= deflate {
+            Some(Encoding::Gzip)
+        } else if deflate > 0.0 {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    // Wrap the writer so everything written to it is compressed. finish() writes the trailer.
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, level: Compression) -> Encoder<'a> {
+        match self {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(writer, level)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(writer, level)),
+        }
+    }
+}
+
+enum Encoder<'a> {
+    Gzip(GzEncoder<&'a mut dyn Write>),
+    Deflate(ZlibEncoder<&'a mut dyn Write>),
+}
+
+impl Encoder<'_> {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish().map(|_| ()),
+            Encoder::Deflate(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for Encoder<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+        }
+    }
+}
+
+pub struct Compress {
+    level: Compression,
+    min_bytes: usize,
+}
+
+impl Compress {
+    pub fn new() -> Self {
+        Compress {
+            level: Compression::default(),
+            min_bytes: 1024,
+        }
+    }
+
+    // 0 (none, fastest) to 9 (smallest). The default, 6, is a good trade.
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = Compression::new(level.min(9));
+        self
+    }
+
+    // Bodies smaller than this are sent as they are. Streamed bodies are always compressed.
+    pub fn min_bytes(mut self, min_bytes: usize) -> Self {
+        self.min_bytes = min_bytes;
+        self
+    }
+
+    // Whether compressing this response could help at all, whatever the client accepts.
+    fn compressible(&self, response: &Response) -> bool {
+        if response.status < 200 || response.status == 204 || response.status == 304 {
+            return false; // No body
+        }
+        // Ranges are of the uncompressed bytes, whether this is one or it offers them.
+        if response.status == 206 || response.header("Accept-Ranges").is_some_and(|ranges| ranges != "none") {
+            return false;
+        }
+        if response.upgrade.is_some() || response.header("Content-Encoding").is_some() {
+            return false;
+        }
+        if response.stream.is_none() && response.body.len() < self.min_bytes {
+            return false;
+        }
+
+        // Formats that are compressed already. Text, JSON, HTML, JS, SVG etc. all shrink well.
+        let content_type = response.header("Content-Type").unwrap_or("").to_ascii_lowercase();
+        let compressed = ["image/", "video/", "audio/", "application/zip", "application/gzip", "font/woff"];
+        !compressed.iter().any(|prefix| content_type.starts_with(prefix)) || content_type == "image/svg+xml"
+    }
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Compress::new()
+    }
+}
+
+impl Middleware for Compress {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let accept_encoding = request.header("Accept-Encoding").map(str::to_string);
+        let head = request.method == "HEAD";
+        let mut response = next.run(request);
+
+        // There's no body to compress in the answer to a HEAD.
+        if head || !self.compressible(&response) {
+            return response;
+        }
+        // The answer depends on Accept-Encoding, so caches must not give a gzipped copy to
+        // a client that didn't ask for one.
+        response.set_header("Vary", "Accept-Encoding");
+
+        let Some(encoding) = accept_encoding.as_deref().and_then(Encoding::negotiate) else {
+            return response;
+        };
+        let level = self.level;
+
+        match response.stream.take() {
+            Some(stream) => {
+                response.stream = Some(Stream::new(move |writer| {
+                    let mut encoder = encoding.encoder(writer, level);
+                    stream.run(&mut encoder)?;
+                    encoder.finish()
+                }));
+            }
+            None => {
+                let mut compressed = Vec::new();
+                let mut encoder = encoding.encoder(&mut compressed, level);
+                // Compressing into a Vec can't fail.
+                encoder.write_all(&response.body).unwrap();
+                encoder.finish().unwrap();
+                response.body = compressed;
+            }
+        }
+        response.set_header("Content-Encoding", encoding.name());
+        // The length is no longer known until it's all been compressed, so a stream is chunked.
+        response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        /* A strong ETag promises the exact bytes, and these aren't the bytes of the original any more.
+        A weak one only promises the same content, which still holds. See static_files.rs.
+        */
+        if let Some(etag) = response.header("ETag").filter(|etag| !etag.starts_with("W/")) {
+            let weak = format!("W/{etag}");
+            response.set_header("ETag", weak);
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::middleware::{Handler, Pipeline};
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(Encoding::negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::negotiate("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::negotiate("br, identity"), None);
+        assert_eq!(Encoding::negotiate("*;q=0"), None);
+    }
+
+    #[test]
+    fn compresses_big_bodies_only() {
+        let app = Pipeline::new(|request: &Request| Response::new(200).with_body("a".repeat(request.path.len() * 100)))
+            .with(Compress::new());
+        let raw = |path: &str| format!("GET {path} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+
+        let small = app.handle(&mut Request::parse(&mut raw("/").as_bytes()).unwrap());
+        assert_eq!(small.header("Content-Encoding"), None);
+
+        let big = app.handle(&mut Request::parse(&mut raw(&"/".repeat(20)).as_bytes()).unwrap());
+        assert_eq!(big.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(big.header("Vary"), Some("Accept-Encoding"));
+
+        let mut body = String::new();
+        GzDecoder::new(&big.body[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "a".repeat(2000));
+    }
+}
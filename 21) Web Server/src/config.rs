@@ -0,0 +1,423 @@
+/* Summary:
+Server settings, read from a config file and/or command line flags.
+
+Flags override the config file, and the config file overrides the defaults. E.g.
+$ cargo run -- --config server.conf --port 8080
+
+The config file has one "key = value" per line, and # starts a comment:
+    address = 127.0.0.1
+    port = 7878
+    workers = 4
+    max_workers = 16      # grow up to 16 workers when busy, leave out to always have 4
+    worker_idle_timeout = 60  # seconds before a worker beyond the first 4 leaves
+    queue_size = 0        # jobs waiting for a worker, 0 for no limit
+    overflow = reject     # when the queue is full: block, reject, drop-oldest or caller-runs
+    mode = threads        # or events, see event_loop.rs
+    doc_root = .
+    read_timeout = 30     # seconds, 0 for no timeout
+    write_timeout = 30
+    request_timeout = 60  # for the whole request to arrive
+    max_header_bytes = 8192
+    max_body_bytes = 1048576
+    access_log = access.log
+    access_log_format = combined  # or common, json
+    access_log_max_bytes = 10485760  # start a new log once it's this big
+    access_log_keep = 5   # old logs to keep
+    https_port = 7443     # needs the tls feature, plus tls_cert and tls_key
+    tls_cert = cert.pem
+    tls_key = key.pem
+    proxy = /api=127.0.0.1:7879,127.0.0.1:7880  # may be repeated, see proxy.rs
+    dev_mode = true       # reload templates when they change
+    vhost = docs.localhost=./docs  # may be repeated, see vhost.rs
+*/
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use crate::{
+    access_log::LogFormat,
+    http::Limits,
+    pool::{Overflow, ThreadPool},
+};
+
+pub const USAGE: &str = "\
+Usage: hello [OPTIONS]
+
+Options:
+    -c, --config <FILE>         Read settings from FILE (flags given after it still win)
+    -a, --address <HOST>        Address to listen on [default: 127.0.0.1]
+    -p, --port <PORT>           Port to listen on [default: 7878]
+    -w, --workers <N>           Number of worker threads [default: 4]
+        --max-workers <N>       Add workers when busy, up to N in all [default: the same as --workers]
+        --worker-idle-timeout <SECS>
+                                Stop a worker beyond --workers that's been idle for SECS [default: 60]
+        --queue-size <N>        Jobs that can wait for a worker, 0 = no limit [default: 0]
+        --overflow <POLICY>     When the queue is full: block, reject (with a 503), drop-oldest
+                                or caller-runs (the accepting thread serves it). In events mode
+                                block and caller-runs act like reject [default: reject]
+    -m, --mode <MODE>           threads: a worker per connection, events: one I/O thread
+                                for all connections, workers only run handlers [default: threads]
+    -r, --doc-root <DIR>        Directory the HTML files are served from [default: .]
+        --read-timeout <SECS>   Give up on a client that sends nothing for SECS, 0 = never [default: 30]
+        --write-timeout <SECS>  Give up on a client that reads nothing for SECS, 0 = never [default: 30]
+        --request-timeout <SECS>
+                                Give up on a request that hasn't all arrived after SECS, 0 = never [default: 60]
+        --max-header-bytes <N>  Refuse requests whose headers are bigger than N bytes [default: 8192]
+        --max-body-bytes <N>    Refuse requests whose body is bigger than N bytes [default: 1048576]
+        --access-log <FILE>     Where to write the access log [default: access.log]
+        --access-log-format <FORMAT>
+                                common, combined or json [default: combined]
+        --access-log-max-bytes <N>
+                                Move the log aside and start a new one at N bytes [default: 10485760]
+        --access-log-keep <N>   How many old logs to keep [default: 5]
+        --https-port <PORT>     Also serve HTTPS on PORT (needs the tls feature)
+        --tls-cert <FILE>       PEM certificate chain for HTTPS
+        --tls-key <FILE>        PEM private key for HTTPS
+        --dev                   Dev mode: reload templates when they change
+        --proxy <PREFIX=HOST:PORT,...>
+                                Forward requests under PREFIX to these servers in turn (repeatable)
+        --vhost <HOST=DIR>      Serve requests for HOST from DIR instead of the doc root (repeatable)
+    -h, --help                  Print this message";
+
+// How connections are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Threads, // Each connection is handled start to finish by one pool worker.
+    Events,  // One thread does all socket I/O, the pool only runs handlers.
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    pub workers: usize,
+    pub max_workers: Option<usize>, // None for a pool that stays at `workers`
+    pub worker_idle_timeout: Duration,
+    pub queue_size: usize, // 0 for no limit
+    pub overflow: Overflow,
+    pub mode: Mode,
+    pub doc_root: PathBuf,
+    pub read_timeout: Option<Duration>, // None waits forever
+    pub write_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+    pub access_log: PathBuf,
+    pub access_log_format: LogFormat,
+    pub access_log_max_bytes: u64,
+    pub access_log_keep: usize,
+    pub https_port: Option<u16>, // None serves plain HTTP only
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub proxies: Vec<(String, Vec<String>)>, // (path prefix, upstream addresses)
+    pub dev_mode: bool,
+    pub vhosts: Vec<(String, PathBuf)>, // (host name, doc root)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "127.0.0.1".to_string(),
+            port: 7878,
+            workers: 4,
+            max_workers: None,
+            worker_idle_timeout: Duration::from_secs(60),
+            queue_size: 0,
+            overflow: Overflow::Reject,
+            mode: Mode::Threads,
+            doc_root: PathBuf::from("."),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            request_timeout: Some(Duration::from_secs(60)),
+            max_header_bytes: Limits::default().max_header_bytes,
+            max_body_bytes: Limits::default().max_body_bytes,
+            access_log: PathBuf::from("access.log"),
+            access_log_format: LogFormat::Combined,
+            access_log_max_bytes: 10 * 1024 * 1024,
+            access_log_keep: 5,
+            https_port: None,
+            tls_cert: None,
+            tls_key: None,
+            proxies: Vec::new(),
+            dev_mode: false,
+            vhosts: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /* Build the config from the CLI args. The first arg is the program's name and is skipped.
+    An Err(USAGE) is returned for --help, so the caller prints the usage either way.
+    */
+    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        args.next();
+
+        let mut config = Config::default();
+
+        while let Some(flag) = args.next() {
+            if flag == "-h" || flag == "--help" {
+                return Err(USAGE.to_string());
+            }
+            // The one flag that doesn't take a value.
+            if flag == "--dev" {
+                config.dev_mode = true;
+                continue;
+            }
+
+            let key = match flag.as_str() {
+                "-c" | "--config" => "config",
+                "-a" | "--address" => "address",
+                "-p" | "--port" => "port",
+                "-w" | "--workers" => "workers",
+                "--max-workers" => "max_workers",
+                "--worker-idle-timeout" => "worker_idle_timeout",
+                "--queue-size" => "queue_size",
+                "--overflow" => "overflow",
+                "-m" | "--mode" => "mode",
+                "-r" | "--doc-root" => "doc_root",
+                "--read-timeout" => "read_timeout",
+                "--write-timeout" => "write_timeout",
+                "--request-timeout" => "request_timeout",
+                "--max-header-bytes" => "max_header_bytes",
+                "--max-body-bytes" => "max_body_bytes",
+                "--access-log" => "access_log",
+                "--access-log-format" => "access_log_format",
+                "--access-log-max-bytes" => "access_log_max_bytes",
+                "--access-log-keep" => "access_log_keep",
+                "--https-port" => "https_port",
+                "--tls-cert" => "tls_cert",
+                "--tls-key" => "tls_key",
+                "--proxy" => "proxy",
+                "--vhost" => "vhost",
+                _ => return Err(format!("unknown option {flag}\n\n{USAGE}")),
+            };
+
+            let Some(value) = args.next() else {
+                return Err(format!("{flag} needs a value"));
+            };
+
+            if key == "config" {
+                config.load_file(&value)?;
+            } else {
+                config.set(key, &value)?;
+            }
+        }
+
+        // Checked once everything's set, as the two can be given in either order.
+        if config.max_workers.is_some_and(|max| max < config.workers) {
+            return Err("max_workers can't be less than workers".to_string());
+        }
+
+        Ok(config)
+    }
+
+    // Apply the settings in a config file on top of the current ones.
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("can't read config file {path}: {e}"))?;
+
+        for (number, line) in contents.lines().enumerate() {
+            // Drop comments, then skip lines that were only whitespace or a comment.
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("{path}:{}: expected key = value", number + 1));
+            };
+
+            self.set(key.trim(), value.trim().trim_matches('"'))
+                .map_err(|e| format!("{path}:{}: {e}", number + 1))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "address" => self.address = value.to_string(),
+            "port" => self.port = parse(key, value)?,
+            "workers" => {
+                self.workers = parse(key, value)?;
+                if self.workers == 0 {
+                    return Err("workers must be at least 1".to_string());
+                }
+            }
+            "max_workers" => self.max_workers = Some(parse(key, value)?),
+            "worker_idle_timeout" => self.worker_idle_timeout = Duration::from_secs(parse(key, value)?),
+            "queue_size" => self.queue_size = parse(key, value)?,
+            "overflow" => {
+                self.overflow = match value {
+                    "block" => Overflow::Block,
+                    "reject" => Overflow::Reject,
+                    "drop-oldest" => Overflow::DropOldest,
+                    "caller-runs" => Overflow::CallerRuns,
+                    _ => return Err(format!("overflow must be block, reject, drop-oldest or caller-runs, not {value}")),
+                }
+            }
+            "mode" => {
+                self.mode = match value {
+                    "threads" => Mode::Threads,
+                    "events" => Mode::Events,
+                    _ => return Err(format!("mode must be threads or events, not {value}")),
+                }
+            }
+            "doc_root" => self.doc_root = PathBuf::from(value),
+            "read_timeout" => self.read_timeout = parse_timeout(key, value)?,
+            "write_timeout" => self.write_timeout = parse_timeout(key, value)?,
+            "request_timeout" => self.request_timeout = parse_timeout(key, value)?,
+            "max_header_bytes" => self.max_header_bytes = parse(key, value)?,
+            "max_body_bytes" => self.max_body_bytes = parse(key, value)?,
+            "access_log" => self.access_log = PathBuf::from(value),
+            "access_log_format" => {
+                self.access_log_format = match value {
+                    "common" => LogFormat::Common,
+                    "combined" => LogFormat::Combined,
+                    "json" => LogFormat::Json,
+                    _ => return Err(format!("access_log_format must be common, combined or json, not {value}")),
+                }
+            }
+            "access_log_max_bytes" => self.access_log_max_bytes = parse(key, value)?,
+            "access_log_keep" => self.access_log_keep = parse(key, value)?,
+            "https_port" => self.https_port = Some(parse(key, value)?),
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)),
+            "dev_mode" => self.dev_mode = parse(key, value)?,
+            // Each proxy setting adds another prefix rather than replacing the last.
+            "proxy" => {
+                let Some((prefix, upstreams)) = value.split_once('=') else {
+                    return Err(format!("proxy must look like /prefix=host:port,host:port, not {value}"));
+                };
+                let upstreams: Vec<String> = upstreams
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|upstream| !upstream.is_empty())
+                    .map(String::from)
+                    .collect();
+                if !prefix.starts_with('/') || upstreams.is_empty() {
+                    return Err(format!("proxy must look like /prefix=host:port,host:port, not {value}"));
+                }
+                self.proxies.push((prefix.trim().to_string(), upstreams));
+            }
+            // Like proxy, each one adds another host.
+            "vhost" => {
+                let Some((host, doc_root)) = value.split_once('=') else {
+                    return Err(format!("vhost must look like host=directory, not {value}"));
+                };
+                if host.trim().is_empty() || doc_root.trim().is_empty() {
+                    return Err(format!("vhost must look like host=directory, not {value}"));
+                }
+                self.vhosts.push((host.trim().to_string(), PathBuf::from(doc_root.trim())));
+            }
+            _ => return Err(format!("unknown setting {key}")),
+        }
+
+        Ok(())
+    }
+
+    // The address to bind, e.g. "127.0.0.1:7878"
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_header_bytes: self.max_header_bytes,
+            max_body_bytes: self.max_body_bytes,
+        }
+    }
+
+    // The pool to serve connections on, with the configured number of workers and queue size.
+    pub fn thread_pool(&self) -> ThreadPool {
+        let mut pool = ThreadPool::builder()
+            .workers(self.workers)
+            .max_workers(self.max_workers.unwrap_or(self.workers))
+            .keep_alive(self.worker_idle_timeout)
+            .thread_name("hello-worker");
+        if self.queue_size > 0 {
+            pool = pool.queue(self.queue_size, self.overflow);
+        }
+        pool.build()
+    }
+
+    pub fn https_bind_address(&self) -> Option<String> {
+        self.https_port.map(|port| format!("{}:{port}", self.address))
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {key}: {value}"))
+}
+
+// Timeouts are given in whole seconds. 0 turns the timeout off.
+fn parse_timeout(key: &str, value: &str) -> Result<Option<Duration>, String> {
+    let secs: u64 = parse(key, value)?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        std::iter::once("hello".to_string()).chain(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let config = Config::build(args(&["-p", "8080", "--workers", "8", "--read-timeout", "0", "--dev", "-m", "events", "--queue-size", "100", "--overflow", "block", "--max-workers", "32"]))
+            .unwrap();
+
+        assert_eq!(config.bind_address(), "127.0.0.1:8080");
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.max_workers, Some(32));
+        assert_eq!(config.worker_idle_timeout, Duration::from_secs(60));
+        assert_eq!(config.queue_size, 100);
+        assert_eq!(config.overflow, Overflow::Block);
+        assert_eq!(config.mode, Mode::Events);
+        assert_eq!(config.read_timeout, None);
+        assert!(config.dev_mode);
+        assert_eq!(config.write_timeout, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(Config::build(args(&["--port", "http"])).is_err());
+        assert!(Config::build(args(&["--workers", "0"])).is_err());
+        assert!(Config::build(args(&["--bogus", "1"])).is_err());
+        assert!(Config::build(args(&["--port"])).is_err());
+        assert!(Config::build(args(&["--proxy", "/api"])).is_err());
+        assert!(Config::build(args(&["--vhost", "=docs"])).is_err());
+        assert!(Config::build(args(&["--overflow", "panic"])).is_err());
+        assert!(Config::build(args(&["--access-log-format", "apache"])).is_err());
+        assert!(Config::build(args(&["--max-workers", "2", "--workers", "4"])).is_err());
+    }
+
+    #[test]
+    fn proxies_add_up() {
+        let config = Config::build(args(&["--proxy", "/api=a:1,b:2", "--proxy", "/old=c:3"])).unwrap();
+
+        assert_eq!(config.proxies, vec![
+            ("/api".to_string(), vec!["a:1".to_string(), "b:2".to_string()]),
+            ("/old".to_string(), vec!["c:3".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn reads_config_file() {
+        let dir = TempDir::new("config");
+        let path = dir.join("hello.conf");
+        fs::write(&path, "# test config\naddress = 0.0.0.0\nport = 9000 # inline comment\n\ndoc_root = \"public\"\naccess_log_format = json\naccess_log_keep = 2\n").unwrap();
+
+        // The flag after --config wins over the file.
+        let config = Config::build(args(&["--config", path.to_str().unwrap(), "--port", "9001"])).unwrap();
+
+        assert_eq!(config.bind_address(), "0.0.0.0:9001");
+        assert_eq!(config.doc_root, PathBuf::from("public"));
+        assert_eq!(config.access_log_format, LogFormat::Json);
+        assert_eq!((config.access_log_max_bytes, config.access_log_keep), (10 * 1024 * 1024, 2));
+    }
+}
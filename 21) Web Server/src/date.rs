@@ -0,0 +1,176 @@
+/* Summary:
+Just enough calendar math to print timestamps, and read back the dates in HTTP headers,
+without pulling in a date crate. All times are UTC.
+*/
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32, // 1..=12
+    pub day: u32,   // 1..=31
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86_400);
+        let time = secs.rem_euclid(86_400) as u32;
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: time / 3600,
+            minute: time % 3600 / 60,
+            second: time % 60,
+        }
+    }
+
+    pub fn to_unix(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86_400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64
+    }
+
+    // The format HTTP headers like Last-Modified use, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+    pub fn to_http(&self) -> String {
+        // 1970-01-01 was a Thursday.
+        let weekday = (days_from_civil(self.year, self.month, self.day) + 4).rem_euclid(7);
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[weekday as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /* Read a date in the format of to_http(). HTTP/1.0 also allowed two older formats, but
+    nothing has sent those in a long time. None means it couldn't be read, and callers should act
+    as if the header wasn't there. The weekday is ignored.
+    */
+    pub fn parse_http(date: &str) -> Option<DateTime> {
+        let (_weekday, rest) = date.split_once(", ")?;
+        let parts: Vec<&str> = rest.split(' ').collect();
+        let [day, month, year, time, "GMT"] = parts.as_slice() else {
+            return None;
+        };
+        let time: Vec<&str> = time.split(':').collect();
+        let [hour, minute, second] = time.as_slice() else {
+            return None;
+        };
+
+        let date = DateTime {
+            year: year.parse().ok()?,
+            month: MONTHS.iter().position(|name| name == month)? as u32 + 1,
+            day: day.parse().ok()?,
+            hour: hour.parse().ok()?,
+            minute: minute.parse().ok()?,
+            second: second.parse().ok()?,
+        };
+        let valid = (1..=31).contains(&date.day) && date.hour < 24 && date.minute < 60 && date.second < 61;
+        valid.then_some(date)
+    }
+
+    // Common Log Format, e.g. "10/Oct/2000:13:55:36 +0000"
+    pub fn to_clf(&self) -> String {
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    // RFC 3339, e.g. "2000-10-10T13:55:36Z"
+    pub fn to_rfc3339(&self) -> String {
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        DateTime::from_unix(secs)
+    }
+}
+
+/* Turn a count of days since 1970-01-01 into (year, month, day).
+This is Howard Hinnant's civil_from_days algorithm. It works in 400-year "eras" that start on
+March 1st, so the leap day is always the last day of the year and falls out naturally.
+*/
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153; // The month, counting from March = 0
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// The inverse of civil_from_days(), from the same source.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64; // The month, counting from March = 0
+    let day_of_year = (153 * mp + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_known_dates() {
+        assert_eq!(DateTime::from_unix(0).to_rfc3339(), "1970-01-01T00:00:00Z");
+        assert_eq!(DateTime::from_unix(971_186_136).to_clf(), "10/Oct/2000:13:55:36 +0000");
+        // Leap day
+        assert_eq!(DateTime::from_unix(951_782_400).to_rfc3339(), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let date = DateTime::from_unix(784_111_777);
+        assert_eq!(date.to_http(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(DateTime::parse_http("Sun, 06 Nov 1994 08:49:37 GMT"), Some(date));
+        assert_eq!(date.to_unix(), 784_111_777);
+
+        assert_eq!(DateTime::parse_http("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(DateTime::parse_http("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+    }
+}
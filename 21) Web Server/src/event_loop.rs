@@ -0,0 +1,561 @@
+/* Summary:
+The event-driven server mode. Select it with `--mode events`.
+
+In thread-pool mode a worker owns a connection from the first byte to the last, so a client
+that sends (or reads) slowly ties up a whole thread. Four slow clients and the server stalls.
+
+Here a single thread watches every socket with mio (epoll on Linux). It only reads when data
+has arrived and only writes when the socket can take more, so any number of slow clients cost
+nothing but a buffer each. Once a whole request has arrived, the handler runs on the pool, and
+the response is passed back to this thread a piece at a time to be written out.
+
+    accept -> read (event loop) -> handler (pool) -> write (event loop) -> close
+
+A handler that blocks, like /sleep, still holds a pool thread while it runs. What this mode
+removes is the time spent waiting on the network. So does a streamed response that the client
+reads slowly: only a few pieces of it are let through at a time, and the handler waits for room.
+Protocol upgrades (WebSockets) aren't supported here and get a 501.
+
+The event loop never waits for the pool. When the queue is full the request gets a 503, whatever
+the pool's Overflow says, rather than this thread running the handler or blocking on it.
+*/
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, prelude::*},
+    net::{self, SocketAddr},
+    mem,
+    sync::{
+        Arc,
+        mpsc::{self, TryRecvError},
+    },
+    time::{Duration, Instant},
+};
+
+use mio::{
+    Events, Interest, Poll, Token, Waker,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    ThreadPool,
+    config::Config,
+    http::{self, Limits, ParseError, Request, Response},
+    middleware::Handler,
+    server::{self, ActiveConnection, Shutdown},
+};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+// How often to wake up and look for connections that have timed out.
+const TICK: Duration = Duration::from_millis(250);
+
+// How long to keep going after a shutdown, for the responses that are still on their way.
+const DRAIN: Duration = Duration::from_secs(30);
+
+// How much of a response a handler gathers before passing it on, and how many pieces can be waiting.
+const PIECE_SIZE: usize = 16 * 1024;
+const PIECES_WAITING: usize = 4;
+
+enum State {
+    Reading,
+    Handling, // The request is on the pool. Nothing to do until the response starts coming back.
+    // Writing out the buffer, then each piece from the handler as it arrives, if there's one.
+    Writing {
+        written: usize,
+        pieces: Option<mpsc::Receiver<Vec<u8>>>,
+    },
+}
+
+// What a handler job sends back to the event loop about its connection.
+enum Reply {
+    Start(mpsc::Receiver<Vec<u8>>), // The response's pieces will come down this.
+    More,                           // Another piece is waiting, or the response is finished.
+}
+
+struct Connection {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    state: State,
+    buffer: Vec<u8>, // The request while reading, the response while writing.
+    progress: Progress, // How much of the request has arrived, so far.
+    accepted: Instant,
+    last_active: Instant,
+    _active: ActiveConnection, // Counted until the connection is dropped.
+}
+
+/* Accept and serve connections until shut down, using one thread for all the I/O and the pool
+for handlers. At shutdown, connections still sending their request are dropped, and the rest get
+up to DRAIN to be answered.
+*/
+pub fn serve_events(
+    listener: net::TcpListener,
+    pool: &ThreadPool,
+    app: Arc<dyn Handler>,
+    config: Arc<Config>,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+
+    let mut poll = Poll::new()?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let mut listener = Some(listener); // Closed at shutdown, so new clients are refused.
+
+    // Workers send replies down the channel, then use the waker to interrupt poll().
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (sender, replies) = mpsc::channel::<(Token, Reply)>();
+
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = 2;
+    let mut events = Events::with_capacity(1024);
+
+    let mut draining: Option<Instant> = None;
+
+    // The shutdown connects to the listener, so poll() returns and the flag is seen straight away.
+    loop {
+        if draining.is_none() && shutdown.is_triggered() {
+            draining = Some(Instant::now());
+            // Closing a socket takes it out of the poll as well.
+            listener = None;
+            connections.retain(|_, connection| !matches!(connection.state, State::Reading));
+        }
+        if draining.is_some_and(|since| connections.is_empty() || since.elapsed() > DRAIN) {
+            break;
+        }
+
+        if let Err(e) = poll.poll(&mut events, Some(TICK)) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => while let Some(listener) = &listener {
+                    // Readiness is only reported once, so accept until there's nobody left waiting.
+                    let (mut stream, remote_addr) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            eprintln!("Accept failed: {e}");
+                            break;
+                        }
+                    };
+
+                    let token = Token(next_token);
+                    next_token += 1;
+                    // Only this connection is lost. Dropping the stream closes it.
+                    if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+                        eprintln!("Couldn't watch a new connection: {e}");
+                        continue;
+                    }
+                    connections.insert(
+                        token,
+                        Connection {
+                            stream,
+                            remote_addr,
+                            state: State::Reading,
+                            buffer: Vec::new(),
+                            progress: Progress::Head { searched: 0 },
+                            accepted: Instant::now(),
+                            last_active: Instant::now(),
+                            _active: ActiveConnection::new(),
+                        },
+                    );
+                },
+
+                WAKER => {
+                    for (token, reply) in replies.try_iter() {
+                        let Some(connection) = connections.get_mut(&token) else {
+                            continue;
+                        };
+                        connection.last_active = Instant::now();
+                        if let Reply::Start(pieces) = reply {
+                            connection.buffer.clear();
+                            connection.state = State::Writing { written: 0, pieces: Some(pieces) };
+                            if let Err(e) = poll.registry().reregister(&mut connection.stream, token, Interest::WRITABLE) {
+                                finish(&mut poll, &mut connections, token, Err(e));
+                                continue;
+                            }
+                        }
+                        // The socket may have been writable all along, and readiness is only reported once.
+                        let done = write_response(connection);
+                        finish(&mut poll, &mut connections, token, done);
+                    }
+                }
+
+                token => {
+                    let Some(connection) = connections.get_mut(&token) else {
+                        continue;
+                    };
+
+                    let done = match connection.state {
+                        State::Reading => read_request(connection, token, pool, &app, &config, &sender, &waker),
+                        State::Writing { .. } => write_response(connection),
+                        State::Handling => Ok(false),
+                    };
+                    finish(&mut poll, &mut connections, token, done);
+                }
+            }
+        }
+
+        // Deal with clients that have gone quiet for longer than the configured timeouts, or are
+        // taking too long to send their request. A client we're still waiting on gets a 408.
+        let now = Instant::now();
+        let expired = |timeout: Option<Duration>, since: Instant| timeout.is_some_and(|timeout| now - since > timeout);
+
+        let mut to_close = Vec::new();
+        for (token, connection) in connections.iter_mut() {
+            match connection.state {
+                State::Reading
+                    if expired(config.read_timeout, connection.last_active)
+                        || expired(config.request_timeout, connection.accepted) =>
+                {
+                    connection.buffer.clear();
+                    connection.state = State::Writing { written: 0, pieces: None };
+                    connection.last_active = now;
+                    let mut response = Response::error(408);
+                    server::report_unhandled(app.as_ref(), Some(connection.remote_addr), None, &response, connection.accepted);
+                    let answered = response
+                        .write_to(&mut connection.buffer)
+                        .and_then(|()| poll.registry().reregister(&mut connection.stream, *token, Interest::WRITABLE));
+                    if let Err(e) = answered {
+                        eprintln!("Connection error: {e}");
+                        to_close.push(*token);
+                    }
+                }
+                State::Writing { .. } if expired(config.write_timeout, connection.last_active) => {
+                    to_close.push(*token);
+                }
+                _ => {}
+            }
+        }
+
+        for token in to_close {
+            close(&mut poll, &mut connections, token);
+        }
+    }
+    Ok(())
+}
+
+/* Read whatever has arrived. Once the whole request is here, send it to the pool.
+Returns Ok(true) when the connection should be closed.
+*/
+fn read_request(
+    connection: &mut Connection,
+    token: Token,
+    pool: &ThreadPool,
+    app: &Arc<dyn Handler>,
+    config: &Config,
+    sender: &mpsc::Sender<(Token, Reply)>,
+    waker: &Arc<Waker>,
+) -> io::Result<bool> {
+    let mut chunk = [0; 4096];
+    let mut closed = false;
+
+    loop {
+        match connection.stream.read(&mut chunk) {
+            Ok(0) => {
+                closed = true;
+                break;
+            }
+            Ok(n) => connection.buffer.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    connection.last_active = Instant::now();
+
+    let parsed = match try_parse(&connection.buffer, &mut connection.progress, &config.limits()) {
+        Some(parsed) => parsed,
+        None if closed => return Ok(true), // They hung up halfway through the request.
+        None => return Ok(false),          // Wait for more.
+    };
+
+    connection.state = State::Handling;
+
+    let mut unanswered = Unanswered {
+        replier: Some(Replier { token, sender: sender.clone(), waker: Arc::clone(waker) }),
+        app: Arc::clone(app),
+        remote_addr: connection.remote_addr,
+        parsed: Some(parsed),
+        accepted: connection.accepted,
+    };
+
+    /* If the pool is full and turns the job away, or drops it later to make room for a newer one
+    (Overflow::DropOldest), dropping it answers the request with a 503.
+    */
+    let _ = pool.try_execute(move || {
+        let parsed = unanswered.parsed.take().unwrap();
+        let response = server::respond(parsed, Some(unanswered.remote_addr), unanswered.app.as_ref(), unanswered.accepted);
+        let response = match response {
+            // The connection can't be handed over to another protocol from here.
+            Ok(Some(response)) if response.upgrade.is_some() => Some(Response::error(501)),
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Connection error: {e}");
+                None
+            }
+        };
+
+        // With nothing to send, the empty response closes the connection.
+        let mut pieces = unanswered.start();
+        if let Some(mut response) = response {
+            // What was written is still sent, and the client can tell it was cut short.
+            if let Err(e) = response.write_to(&mut pieces) {
+                eprintln!("Response body failed: {e}");
+            }
+        }
+    });
+
+    Ok(false)
+}
+
+/* A request that's waiting for a worker. Unless start() is called, it's answered with a 503 when
+it's dropped, so the connection never waits in State::Handling for a job that's gone.
+*/
+struct Unanswered {
+    replier: Option<Replier>,
+    app: Arc<dyn Handler>, // Told about the 503, since it never saw the request.
+    remote_addr: SocketAddr,
+    parsed: Option<Result<Request, ParseError>>,
+    accepted: Instant,
+}
+
+impl Unanswered {
+    fn start(mut self) -> Pieces {
+        self.replier.take().unwrap().start()
+    }
+}
+
+impl Drop for Unanswered {
+    fn drop(&mut self) {
+        if let Some(replier) = self.replier.take() {
+            // The 503 goes back the same way a response would.
+            let mut response = Response::error(503).with_header("Retry-After", "1");
+            let request = self.parsed.as_ref().and_then(|parsed| parsed.as_ref().ok());
+            server::report_unhandled(self.app.as_ref(), Some(self.remote_addr), request, &response, self.accepted);
+            let _ = response.write_to(&mut replier.start());
+        }
+    }
+}
+
+// How a handler job reaches the event loop about one connection.
+struct Replier {
+    token: Token,
+    sender: mpsc::Sender<(Token, Reply)>,
+    waker: Arc<Waker>,
+}
+
+impl Replier {
+    fn send(&self, reply: Reply) {
+        // If the event loop is gone the server is shutting down, and there's nobody to tell.
+        if self.sender.send((self.token, reply)).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+
+    // Tell the event loop a response is coming, and return somewhere to write it.
+    fn start(self) -> Pieces {
+        let (sender, receiver) = mpsc::sync_channel(PIECES_WAITING);
+        self.send(Reply::Start(receiver));
+        Pieces { piece: Vec::new(), sender: Some(sender), replier: self }
+    }
+}
+
+/* A response on its way to the event loop. What's written is gathered into pieces of PIECE_SIZE
+and sent over one at a time. The response is finished when this is dropped.
+*/
+struct Pieces {
+    piece: Vec<u8>,
+    sender: Option<mpsc::SyncSender<Vec<u8>>>,
+    replier: Replier,
+}
+
+impl Write for Pieces {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.piece.extend_from_slice(buf);
+        if self.piece.len() >= PIECE_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    /* Send what's been written so far. When the client is behind by PIECES_WAITING pieces this
+    waits for it to catch up, which holds the worker but never more than a few pieces of memory.
+    Once the connection is closed the receiver is gone, and the handler gets a BrokenPipe.
+    */
+    fn flush(&mut self) -> io::Result<()> {
+        if self.piece.is_empty() {
+            return Ok(());
+        }
+        let piece = mem::take(&mut self.piece);
+        let sent = self.sender.as_ref().is_some_and(|sender| sender.send(piece).is_ok());
+        if !sent {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "the connection was closed"));
+        }
+        self.replier.send(Reply::More);
+        Ok(())
+    }
+}
+
+impl Drop for Pieces {
+    // Send whatever's left, then hang up so the event loop knows the response is finished.
+    fn drop(&mut self) {
+        let _ = self.flush();
+        self.sender = None;
+        self.replier.send(Reply::More);
+    }
+}
+
+// How far a request has got, so what's already arrived isn't looked through again each time.
+enum Progress {
+    Head { searched: usize }, // How much of the buffer has been searched for the end of the headers.
+    Body { length: usize },   // The headers are in, and the request is this long in all.
+    Chunked,                  // The headers are in, and the body ends after its last chunk.
+}
+
+/* Parse the request if all of it has arrived, or None if we need to wait for more.
+A client may send its request a byte at a time, and parsing it all over again each time would
+cost time in the square of its length. So first only the new bytes are searched for the blank
+line that ends the headers. Then the headers say how long the body is, and the request isn't
+parsed again until that much has arrived. A chunked body's length isn't known, but it ends with
+a blank line too, so it's only parsed when the buffer does, or it's too big to wait for.
+*/
+fn try_parse(buffer: &[u8], progress: &mut Progress, limits: &Limits) -> Option<Result<Request, ParseError>> {
+    if let Progress::Head { searched } = progress {
+        // The blank line may have started at the end of what was searched before.
+        let from = searched.saturating_sub(3);
+        let Some(end) = buffer[from..].windows(4).position(|window| window == b"\r\n\r\n") else {
+            *searched = buffer.len();
+            // No point waiting for the end of headers that are already too big.
+            if buffer.len() > limits.max_header_bytes {
+                return Some(Err(ParseError::HeadersTooLarge));
+            }
+            return None;
+        };
+        let head_length = from + end + 4;
+
+        let head = match Request::parse_head(&mut &buffer[..head_length], limits) {
+            Ok(head) => head,
+            Err(e) => return Some(Err(e)),
+        };
+        // A length that's invalid or too big is parsed straight away, to get the error for it.
+        let body_length = head
+            .header("Content-Length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|&length| length <= limits.max_body_bytes)
+            .unwrap_or(0);
+        *progress = if http::is_chunked(&head.headers) {
+            Progress::Chunked
+        } else {
+            Progress::Body { length: head_length + body_length }
+        };
+    }
+
+    let complete = match *progress {
+        Progress::Head { .. } => false,
+        Progress::Body { length } => buffer.len() >= length,
+        // Every chunk costs a few bytes of framing besides its data, so allow for some of that.
+        Progress::Chunked => buffer.ends_with(b"\r\n\r\n") || buffer.len() > 2 * limits.max_body_bytes + limits.max_header_bytes,
+    };
+    if !complete {
+        return None;
+    }
+
+    // After that, Request::parse reports a short body as UnexpectedEof.
+    match Request::parse_with_limits(&mut &buffer[..], limits) {
+        Err(ParseError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => None,
+        parsed => Some(parsed),
+    }
+}
+
+/* Write as much of the response as the socket will take, moving on to the handler's next piece
+whenever the buffer's been sent. Returns Ok(true) once it's all sent and there's no more to come.
+*/
+fn write_response(connection: &mut Connection) -> io::Result<bool> {
+    let State::Writing { written, pieces } = &mut connection.state else {
+        return Ok(false);
+    };
+
+    loop {
+        while *written < connection.buffer.len() {
+            match connection.stream.write(&connection.buffer[*written..]) {
+                Ok(n) => {
+                    *written += n;
+                    connection.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let Some(receiver) = pieces else {
+            return Ok(true);
+        };
+        match receiver.try_recv() {
+            Ok(piece) => {
+                connection.buffer = piece;
+                *written = 0;
+            }
+            // The handler's still working on it, and sends Reply::More when there's another piece.
+            Err(TryRecvError::Empty) => return Ok(false),
+            Err(TryRecvError::Disconnected) => return Ok(true),
+        }
+    }
+}
+
+// Close the connection once it's done with, or if anything went wrong.
+fn finish(poll: &mut Poll, connections: &mut HashMap<Token, Connection>, token: Token, done: io::Result<bool>) {
+    match done {
+        Ok(false) => {}
+        Ok(true) => close(poll, connections, token),
+        Err(e) => {
+            eprintln!("Connection error: {e}");
+            close(poll, connections, token);
+        }
+    }
+}
+
+fn close(poll: &mut Poll, connections: &mut HashMap<Token, Connection>, token: Token) {
+    if let Some(mut connection) = connections.remove(&token) {
+        let _ = poll.registry().deregister(&mut connection.stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed try_parse() the request a byte at a time, like a slow client, and return what it made of it.
+    fn parse_slowly(raw: &[u8]) -> (Progress, Request) {
+        let limits = Limits::default();
+        let mut progress = Progress::Head { searched: 0 };
+        for end in 1..raw.len() {
+            assert!(try_parse(&raw[..end], &mut progress, &limits).is_none(), "parsed after {end} bytes");
+        }
+        let request = try_parse(raw, &mut progress, &limits).unwrap().unwrap();
+        (progress, request)
+    }
+
+    #[test]
+    fn requests_sent_a_byte_at_a_time_are_only_parsed_once_complete() {
+        let raw = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let (progress, request) = parse_slowly(raw);
+        assert!(matches!(progress, Progress::Body { length } if length == raw.len()));
+        assert_eq!(request.body, b"hello");
+
+        let (progress, request) = parse_slowly(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n");
+        assert!(matches!(progress, Progress::Chunked));
+        assert_eq!(request.body, b"hello");
+
+        let (progress, request) = parse_slowly(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(matches!(progress, Progress::Body { length: 35 }));
+        assert_eq!(request.path, "/");
+    }
+}
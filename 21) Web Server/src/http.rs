@@ -0,0 +1,718 @@
+/* Summary:
+The HTTP types passed between the server, the middleware, and the handlers.
+A Request is parsed from the stream, and a Response is written back to it.
+
+A response body is normally a Vec<u8> that's sent with a Content-Length. A big generated page or
+file doesn't have to be held in memory whole, though. Give the response a Stream instead, and
+the body is written out as it's produced, in chunks:
+
+    HTTP/1.1 200 OK
+    Transfer-Encoding: chunked
+
+    2000\r\n
+    ...8192 bytes...\r\n
+    0\r\n
+    \r\n
+*/
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, prelude::*},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/* The stream a request arrives on, e.g. a TcpStream or a TLS stream.
+Besides reading and writing, a protocol that takes over the connection after an Upgrade
+(like WebSockets) needs to be able to wait for data with a timeout.
+*/
+pub trait Connection: Read + Write {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub remote_addr: Option<SocketAddr>,
+}
+
+// How big a request may be. Anything bigger is refused before it's read into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_header_bytes: usize, // The request line and all the headers together
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_bytes: 8 * 1024,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+// Why a request couldn't be read.
+#[derive(Debug)]
+pub enum ParseError {
+    Malformed(&'static str),
+    HeadersTooLarge,
+    BodyTooLarge,
+    Io(io::Error), // Including timeouts, and the client hanging up
+}
+
+impl ParseError {
+    /* The status code to answer with, or None if there's nobody left to answer.
+    Other I/O errors have no status either, but are worth reporting.
+    */
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ParseError::Malformed(_) => Some(400),
+            ParseError::HeadersTooLarge => Some(431),
+            ParseError::BodyTooLarge => Some(413),
+            // A socket read timeout shows up as WouldBlock on Unix and TimedOut on Windows.
+            ParseError::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Some(408),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Malformed(what) => write!(f, "malformed request: {what}"),
+            ParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+// Lets the ? operator turn an io::Error into a ParseError.
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+impl Request {
+    // Read a request with the default limits.
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::parse_with_limits(reader, &Limits::default())
+    }
+
+    /* Read a request from the stream: the request line, then the headers up to the blank line,
+    then a body if a Content-Length was sent, or a chunked one.
+    */
+    pub fn parse_with_limits<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let mut request = Request::parse_head(reader, limits)?;
+
+        if is_chunked(&request.headers) {
+            copy_chunked(reader, &mut request.body, limits)?;
+            remove_header(&mut request.headers, "Transfer-Encoding");
+        } else if let Some(length) = request.header("Content-Length") {
+            request.body = read_body(reader, length, limits)?;
+        }
+
+        Ok(request)
+    }
+
+    // Read only the request line and the headers, and leave the body, if any, in the reader.
+    pub fn parse_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        // Every line read is taken out of this, so the headers can't grow past the limit.
+        let mut header_budget = limits.max_header_bytes;
+
+        let request_line = read_line(reader, &mut header_budget)?;
+
+        // A request line looks like "GET /index.html HTTP/1.1"
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::Malformed("request line"));
+        };
+
+        let mut request = Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            version: version.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote_addr: None,
+        };
+
+        request.headers = read_headers(reader, &mut header_budget)?;
+        Ok(request)
+    }
+
+    // Header names are case-insensitive, so "content-length" and "Content-Length" are the same header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        set_header(&mut self.headers, name, value.into());
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // If set, the body is written by this instead, and `body` is ignored.
+    pub stream: Option<Stream>,
+    // Set on a 101 Switching Protocols response. Called with the connection once the response is sent.
+    pub upgrade: Option<Upgrade>,
+}
+
+/* Writes a response body as it's produced. It's called with the connection once the headers are
+sent, and everything it writes is sent as chunks. Returning an error cuts the response off, and
+the client can tell, since the last chunk never arrives.
+If the response has a Content-Length header, the stream must write exactly that many bytes, and
+they're sent without chunking.
+*/
+pub struct Stream(StreamFn);
+
+type StreamFn = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+impl Stream {
+    pub fn new(f: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static) -> Self {
+        Stream(Box::new(f))
+    }
+
+    // A body read from e.g. a file, a little at a time.
+    pub fn from_reader(mut reader: impl Read + Send + 'static) -> Self {
+        Stream::new(move |writer| io::copy(&mut reader, writer).map(|_| ()))
+    }
+
+    pub fn run(self, writer: &mut dyn Write) -> io::Result<()> {
+        (self.0)(writer)
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Stream(..)")
+    }
+}
+
+/* What to do with the connection after switching protocols. Once this is called the
+connection is no longer HTTP, and it's closed when this returns.
+*/
+pub struct Upgrade(UpgradeFn);
+
+type UpgradeFn = Box<dyn FnOnce(&mut dyn Connection) + Send>;
+
+impl Upgrade {
+    pub fn new(f: impl FnOnce(&mut dyn Connection) + Send + 'static) -> Self {
+        Upgrade(Box::new(f))
+    }
+
+    pub fn run(self, connection: &mut dyn Connection) {
+        (self.0)(connection)
+    }
+}
+
+// Closures can't be printed, so just say that there is one.
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade(..)")
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+            upgrade: None,
+        }
+    }
+
+    // Read a response with the default limits.
+    pub fn parse<R: BufRead>(reader: &mut R, request_method: &str) -> Result<Response, ParseError> {
+        Response::parse_with_limits(reader, request_method, &Limits::default())
+    }
+
+    /* Read a response, e.g. from an upstream server. The body ends after its Content-Length,
+    after the last chunk if it's chunked, or else when the server closes the connection.
+    The request's method is needed because the response to a HEAD never has a body.
+    */
+    pub fn parse_with_limits<R: BufRead>(
+        reader: &mut R,
+        request_method: &str,
+        limits: &Limits,
+    ) -> Result<Response, ParseError> {
+        let mut header_budget = limits.max_header_bytes;
+
+        // A status line looks like "HTTP/1.1 404 Not Found"
+        let status_line = read_line(reader, &mut header_budget)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+            return Err(ParseError::Malformed("status line"));
+        };
+        let status: u16 = status.parse().map_err(|_| ParseError::Malformed("status code"))?;
+        if !version.starts_with("HTTP/") {
+            return Err(ParseError::Malformed("status line"));
+        }
+
+        let mut response = Response::new(status);
+        response.headers = read_headers(reader, &mut header_budget)?;
+
+        if !response.has_body(request_method) {
+            return Ok(response);
+        }
+
+        if is_chunked(&response.headers) {
+            copy_chunked(reader, &mut response.body, limits)?;
+            remove_header(&mut response.headers, "Transfer-Encoding");
+        } else if let Some(length) = response.header("Content-Length") {
+            response.body = read_body(reader, length, limits)?;
+        } else {
+            // No length given, so the body is everything until the connection closes.
+            reader
+                .take(limits.max_body_bytes as u64 + 1)
+                .read_to_end(&mut response.body)?;
+            if response.body.len() > limits.max_body_bytes {
+                return Err(ParseError::BodyTooLarge);
+            }
+        }
+
+        Ok(response)
+    }
+
+    /* Read only the status line and headers, and leave the body to be streamed from the reader
+    as it's written out. Used to pass an upstream's response on without buffering it.
+    */
+    pub fn parse_streaming<R: BufRead + Send + 'static>(
+        mut reader: R,
+        request_method: &str,
+        limits: &Limits,
+    ) -> Result<Response, ParseError> {
+        let mut response = Response::parse_with_limits(&mut reader, "HEAD", limits)?;
+        if !response.has_body(request_method) {
+            return Ok(response);
+        }
+
+        if is_chunked(&response.headers) {
+            // The body can be any length, but each size line is still limited.
+            let limits = Limits {
+                max_body_bytes: usize::MAX,
+                ..*limits
+            };
+            response.stream = Some(Stream::new(move |writer| {
+                copy_chunked(&mut reader, writer, &limits).map_err(|e| match e {
+                    ParseError::Io(e) => e,
+                    e => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+                })
+            }));
+        } else if let Some(length) = response.header("Content-Length") {
+            let length: u64 = length.parse().map_err(|_| ParseError::Malformed("Content-Length"))?;
+            response.stream = Some(Stream::new(move |writer| {
+                if io::copy(&mut reader.take(length), writer)? < length {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "body cut short"));
+                }
+                Ok(())
+            }));
+        } else {
+            response.stream = Some(Stream::from_reader(reader));
+        }
+        remove_header(&mut response.headers, "Transfer-Encoding");
+        remove_header(&mut response.headers, "Content-Length");
+
+        Ok(response)
+    }
+
+    // 1xx, 204 and 304 responses never have a body, and neither does the response to a HEAD.
+    fn has_body(&self, request_method: &str) -> bool {
+        request_method != "HEAD" && self.status >= 200 && self.status != 204 && self.status != 304
+    }
+
+    // A short plain text response for errors, e.g. "400 Bad Request".
+    pub fn error(status: u16) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!("{status} {}\n", reason_phrase(status)))
+    }
+
+    // Builder style, so a handler can write Response::new(200).with_header(..).with_body(..)
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Send the body in chunks as it's written, rather than all at once. See Stream.
+    pub fn with_stream(mut self, f: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static) -> Self {
+        self.stream = Some(Stream::new(f));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        set_header(&mut self.headers, name, value.into());
+    }
+
+    /* Write the status line, headers, and body to the stream.
+    The Content-Length is always computed from the body, so handlers don't have to.
+    A streamed body is sent chunked instead, which is why this takes the stream out of the response.
+    1xx, 204 and 304 responses can't have a body, so they don't get one. A 304 may still say how long
+    the body it stands in for is, if the handler gave it a Content-Length.
+    */
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding") {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        let has_body = self.status >= 200 && self.status != 204 && self.status != 304;
+        let stream = self.stream.take().filter(|_| has_body);
+        // A stream whose length is known up front (e.g. a file) is sent as it is, unchunked.
+        let length = self.header("Content-Length").filter(|_| stream.is_some() || self.status == 304);
+        let chunked = stream.is_some() && length.is_none();
+        if let Some(length) = length {
+            head.push_str(&format!("Content-Length: {length}\r\n"));
+        } else if chunked {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        } else if has_body {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        match stream {
+            Some(stream) if chunked => {
+                let mut chunked = ChunkedWriter::new(&mut *writer);
+                stream.run(&mut chunked)?;
+                chunked.finish()?;
+            }
+            Some(stream) => stream.run(&mut *writer)?,
+            None => writer.write_all(&self.body)?,
+        }
+        writer.flush()
+    }
+}
+
+/* Turns writes into chunks. Small writes are gathered up first, so writing a page a few bytes
+at a time doesn't send a chunk header for every few bytes. flush() sends what's gathered so far.
+*/
+struct ChunkedWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>,
+}
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(writer: W) -> Self {
+        ChunkedWriter {
+            writer,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(()); // An empty chunk would end the body.
+        }
+        write!(self.writer, "{:x}\r\n", self.buffer.len())?;
+        self.buffer.extend_from_slice(b"\r\n");
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // Send whatever's left, then the empty chunk that marks the end of the body.
+    fn finish(mut self) -> io::Result<()> {
+        self.send_chunk()?;
+        self.writer.write_all(b"0\r\n\r\n")
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()?;
+        self.writer.flush()
+    }
+}
+
+/* The standard reason phrases (RFC 9110, plus 429 from RFC 6585), so any status a handler picks
+gets a proper status line. A code with no standard phrase gets an empty one, which the status line
+still allows.
+*/
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/* Read one line and strip the "\r\n". At most `budget` bytes are read, and the budget is reduced
+by the length of the line. Running out of input before the line ends is an error.
+*/
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<String, ParseError> {
+    let mut line = Vec::new();
+    let n = reader.take(*budget as u64).read_until(b'\n', &mut line)?;
+
+    if !line.ends_with(b"\n") {
+        if n == *budget {
+            return Err(ParseError::HeadersTooLarge); // Stopped by the limit, not the end of the line.
+        }
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed").into());
+    }
+    *budget -= n;
+
+    let line = String::from_utf8(line).map_err(|_| ParseError::Malformed("not UTF-8"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Read header lines up to and including the blank line that ends them.
+fn read_headers<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Vec<(String, String)>, ParseError> {
+    let mut headers = Vec::new();
+
+    loop {
+        let line = read_line(reader, budget)?;
+        if line.is_empty() {
+            return Ok(headers); // The blank line ends the headers.
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::Malformed("header"));
+        };
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+// Read a body of a given Content-Length.
+fn read_body<R: BufRead>(reader: &mut R, length: &str, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let length: usize = length.parse().map_err(|_| ParseError::Malformed("Content-Length"))?;
+    // Check before allocating, so a made up Content-Length can't exhaust memory.
+    if length > limits.max_body_bytes {
+        return Err(ParseError::BodyTooLarge);
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+pub(crate) fn is_chunked(headers: &[(String, String)]) -> bool {
+    find_header(headers, "Transfer-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+}
+
+/* Copy a chunked body to the writer. Each chunk is its size in hex on a line, then that many
+bytes and a line break. A chunk of size 0 ends the body, and may be followed by trailer headers,
+which are dropped. Each size line may be as long as the headers can be, and so may the trailers
+all together. There can only be as many chunks as there are bytes in the body, since the body
+limit bounds those lines too.
+
+    5\r\n
+    Hello\r\n
+    0\r\n
+    \r\n
+*/
+fn copy_chunked<R: BufRead, W: Write + ?Sized>(reader: &mut R, writer: &mut W, limits: &Limits) -> Result<(), ParseError> {
+    let mut total: usize = 0;
+
+    loop {
+        let mut line_budget = limits.max_header_bytes;
+        let line = read_line(reader, &mut line_budget)?;
+        let size = line.split(';').next().unwrap().trim(); // Ignore any chunk extensions
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::Malformed("chunk size"))?;
+
+        if size == 0 {
+            let mut trailer_budget = limits.max_header_bytes;
+            read_headers(reader, &mut trailer_budget)?;
+            return Ok(());
+        }
+        total = total.saturating_add(size);
+        if total > limits.max_body_bytes {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        if io::copy(&mut reader.take(size as u64), writer)? < size as u64 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "chunk cut short").into());
+        }
+
+        let mut line_budget = limits.max_header_bytes;
+        if !read_line(reader, &mut line_budget)?.is_empty() {
+            return Err(ParseError::Malformed("chunk"));
+        }
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn remove_header(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+}
+
+// Replace the header if it's already there, otherwise add it.
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: String) {
+    match headers.iter_mut().find(|(header, _)| header.eq_ignore_ascii_case(name)) {
+        Some((_, existing)) => *existing = value,
+        None => headers.push((name.to_string(), value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chunked_response() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let response = Response::parse(&mut raw.as_bytes(), "GET").unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"Hello, world");
+        assert_eq!(response.header("Transfer-Encoding"), None);
+    }
+
+    #[test]
+    fn many_small_chunks_fit_in_the_body_limit() {
+        let chunks = "1\r\na\r\n".repeat(5000);
+        let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}0\r\n\r\n");
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.body, "a".repeat(5000).as_bytes());
+
+        // A single size line is still held to the header limit.
+        let limits = Limits {
+            max_header_bytes: 64,
+            ..Limits::default()
+        };
+        let extension = "x".repeat(100);
+        let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{extension}\r\na\r\n0\r\n\r\n");
+        assert!(matches!(Request::parse_with_limits(&mut raw.as_bytes(), &limits), Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn response_without_length_reads_to_end() {
+        let raw = "HTTP/1.0 404 Not Found\r\n\r\ngone";
+
+        assert_eq!(Response::parse(&mut raw.as_bytes(), "GET").unwrap().body, b"gone");
+        assert!(Response::parse(&mut raw.as_bytes(), "HEAD").unwrap().body.is_empty());
+    }
+
+    #[test]
+    fn status_lines_have_standard_reason_phrases() {
+        let mut sent = Vec::new();
+        Response::new(429).write_to(&mut sent).unwrap();
+        assert!(sent.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+
+        assert_eq!(reason_phrase(201), "Created");
+        assert_eq!(reason_phrase(301), "Moved Permanently");
+        assert_eq!(reason_phrase(403), "Forbidden");
+        assert_eq!(Response::error(403).body, b"403 Forbidden\n");
+        assert_eq!(reason_phrase(599), "");
+    }
+
+    #[test]
+    fn streamed_body_is_sent_chunked() {
+        let mut response = Response::new(200).with_stream(|writer| {
+            writer.write_all(b"Hello")?;
+            writer.flush()?; // Sends what's been written so far as a chunk.
+            writer.write_all(b", world")
+        });
+        let mut sent = Vec::new();
+        response.write_to(&mut sent).unwrap();
+
+        let sent = String::from_utf8(sent).unwrap();
+        assert!(sent.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!sent.contains("Content-Length"));
+        assert!(sent.ends_with("\r\n\r\n5\r\nHello\r\n7\r\n, world\r\n0\r\n\r\n"));
+
+        // And it reads back as the whole body.
+        let parsed = Response::parse(&mut sent.as_bytes(), "GET").unwrap();
+        assert_eq!(parsed.body, b"Hello, world");
+    }
+}
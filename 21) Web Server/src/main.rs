@@ -1,46 +1,137 @@
 /* Summary:
 A multithreaded HTTP server example.
+
+$ cargo run -- --help
+lists the options. E.g. to run a second instance beside the first:
+$ cargo run -- --port 7879 --access-log access-7879.log
+and to pass /api on to it from the first:
+$ cargo run -- --proxy /api=127.0.0.1:7879
+
+Built with `--features tls`, it can serve HTTPS on a second port as well. See src/tls.rs.
 */
 
-use std::{
-    fs,
-    io::{BufReader, prelude::*},
-    net::{TcpListener, TcpStream}, thread, time::Duration,
-};
+use std::{env, net::TcpListener, process, sync::Arc, time::Duration};
 
-use hello::ThreadPool;
+use hello::{
+    ThreadPool, app,
+    access_log::AccessLog,
+    config::{Config, Mode},
+    event_loop::serve_events,
+    middleware::Handler,
+    server::{Shutdown, serve_threads},
+};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let config = Config::build(env::args()).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
+
+    // A taken port is an everyday mistake, so report it instead of panicking.
+    let listener = TcpListener::bind(config.bind_address()).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {err}", config.bind_address());
+        process::exit(1);
+    });
+    // The HTTPS listener, if there is one, runs on its own thread and shares the pool.
+    let pool = Arc::new(config.thread_pool());
 
-    for stream in listener.incoming() { // A stream is an open connection between client and server.
-        let stream = stream.unwrap();
+    let access_log = AccessLog::to_file(
+        &config.access_log,
+        config.access_log_format,
+        config.access_log_max_bytes,
+        config.access_log_keep,
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Could not open access log {}: {err}", config.access_log.display());
+        process::exit(1);
+    });
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    let app: Arc<dyn Handler> = Arc::new(app::new(&config, access_log, &pool));
+
+    let config = Arc::new(config);
+
+    if config.https_port.is_some() {
+        start_https(Arc::clone(&config), Arc::clone(&pool), Arc::clone(&app));
     }
 
-    println!("Shutting down.")
-}
+    println!("Listening on {} ({:?} mode)", config.bind_address(), config.mode);
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
+    // Nothing triggers this yet, so the server runs until it's killed.
+    let shutdown = Shutdown::new(&listener).unwrap_or_else(|err| {
+        eprintln!("Could not read the listening address: {err}");
+        process::exit(1);
+    });
 
-    let (status, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "hello.html")
+    match config.mode {
+        Mode::Threads => serve_threads(listener, &pool, app, config, shutdown),
+        Mode::Events => {
+            if let Err(e) = serve_events(listener, &pool, app, config, shutdown) {
+                eprintln!("Event loop failed: {e}");
+                process::exit(1);
+            }
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html")
+    }
+
+    println!("Shutting down.");
+    // Give the connections still being served a while to finish. The HTTPS thread, if there is
+    // one, never lets go of the pool, whose workers then just stop when the process exits.
+    if let Ok(pool) = Arc::try_unwrap(pool) {
+        let report = pool.shutdown_graceful(Duration::from_secs(30));
+        println!("{} jobs finished, {} abandoned.", report.completed, report.abandoned);
+    }
+}
+
+// Serve the same app over HTTPS, from a second listener.
+#[cfg(feature = "tls")]
+fn start_https(config: Arc<Config>, pool: Arc<ThreadPool>, app: Arc<dyn Handler>) {
+    use std::thread;
+
+    use hello::tls::TlsAcceptor;
+
+    let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+        eprintln!("HTTPS needs both --tls-cert and --tls-key");
+        process::exit(1);
     };
+    let acceptor = Arc::new(TlsAcceptor::from_pem_files(cert, key).unwrap_or_else(|err| {
+        eprintln!("Could not load TLS certificate: {err}");
+        process::exit(1);
+    }));
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
-    let response = format!("{status}\r\nContent-length: {length}\r\n\r\n{contents}");
+    let address = config.https_bind_address().unwrap();
+    let listener = TcpListener::bind(&address).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {address}: {err}");
+        process::exit(1);
+    });
+    println!("Listening on {address} (HTTPS)");
 
-    stream.write_all(response.as_bytes()).unwrap();
-}
\ No newline at end of file
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Accept failed: {e}");
+                    continue;
+                }
+            };
+            let acceptor = Arc::clone(&acceptor);
+            let app = Arc::clone(&app);
+            let config = Arc::clone(&config);
+
+            // A full pool can't send a 503 here: that would need the TLS handshake first.
+            let served = pool.execute(move || {
+                if let Err(e) = acceptor.serve(stream, &config, app.as_ref()) {
+                    eprintln!("TLS connection error: {e}");
+                }
+            });
+            if served.is_err() {
+                eprintln!("Too busy; turned a TLS connection away");
+            }
+        }
+    });
+}
+
+#[cfg(not(feature = "tls"))]
+fn start_https(_config: Arc<Config>, _pool: Arc<ThreadPool>, _app: Arc<dyn Handler>) {
+    eprintln!("HTTPS support isn't compiled in. Rebuild with: cargo run --features tls");
+    process::exit(1);
+}
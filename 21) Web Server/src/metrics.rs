@@ -0,0 +1,232 @@
+/* Summary:
+Request and pool statistics, served at /metrics in the Prometheus text format:
+$ curl localhost:7878/metrics
+
+    http_requests_total{route="/",status="200"} 12
+    http_request_duration_seconds_bucket{route="/",le="0.005"} 11
+    ...
+    http_active_connections 3
+    threadpool_workers{state="busy"} 4
+    threadpool_workers{state="idle"} 0
+    threadpool_queue_depth 9
+    threadpool_jobs_dropped_total 0
+    threadpool_timer_runs_skipped_total 0
+
+Metrics is a middleware. It counts and times every request that passes through it, and answers
+requests for /metrics itself, so no route is needed for it.
+
+Requests are labeled by route, not by path. Every distinct path would be a new series, and a
+client could make up paths until the metrics were bigger than the server's memory. So only the
+routes given to Metrics::route() are used as labels, and everything else counts as "other".
+
+A queue depth that keeps growing while no workers are idle means the pool is too small.
+*/
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    PoolMonitor,
+    http::{Request, Response},
+    middleware::{Middleware, Next},
+    server,
+};
+
+// Upper bounds of the latency histogram's buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+pub struct Metrics {
+    path: String,
+    routes: Vec<String>,
+    pool: Option<PoolMonitor>,
+    requests: Mutex<BTreeMap<(String, u16), u64>>, // (route, status) -> count
+    latencies: Mutex<BTreeMap<String, Histogram>>,  // route -> histogram
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()], // How many requests took at most BUCKETS[i]. Not cumulative.
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            path: "/metrics".to_string(),
+            routes: Vec::new(),
+            pool: None,
+            requests: Mutex::new(BTreeMap::new()),
+            latencies: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Serve the metrics at this path instead of /metrics.
+    pub fn at(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /* Label requests for this route with its own name. "/" only matches the home page; any
+    other route also covers the paths below it, e.g. "/api" covers "/api/users".
+    */
+    pub fn route(mut self, route: &str) -> Self {
+        self.routes.push(route.to_string());
+        self
+    }
+
+    // Report on this pool's workers and queue too.
+    pub fn pool(mut self, pool: PoolMonitor) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    // The route label for a path: the longest matching route, or "other".
+    fn route_of(&self, path: &str) -> &str {
+        let path = path.split('?').next().unwrap();
+
+        self.routes
+            .iter()
+            .filter(|route| {
+                let route = route.trim_end_matches('/');
+                path == route
+                    || path == "/" && route.is_empty()
+                    || !route.is_empty() && path.strip_prefix(route).is_some_and(|rest| rest.starts_with('/'))
+            })
+            .max_by_key(|route| route.len())
+            .map_or("other", String::as_str)
+    }
+
+    fn record(&self, route: &str, status: u16, duration: Duration) {
+        *self.requests.lock().unwrap().entry((route.to_string(), status)).or_default() += 1;
+
+        let seconds = duration.as_secs_f64();
+        let mut latencies = self.latencies.lock().unwrap();
+        let histogram = latencies.entry(route.to_string()).or_default();
+        // Slower than the last bucket only shows up in the sum and count, i.e. the +Inf bucket.
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    // Everything, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        // Writing to a String can't fail, so the results of writeln! are ignored throughout.
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests handled, by route and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "http_requests_total{{route=\"{}\",status=\"{status}\"}} {count}", escape(route));
+        }
+
+        out.push_str("# HELP http_request_duration_seconds Time taken to handle requests, by route.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for (route, histogram) in self.latencies.lock().unwrap().iter() {
+            let route = escape(route);
+            // Prometheus buckets are cumulative: each counts every request up to its bound.
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{route}\",le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{route}\",le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{route=\"{route}\"}} {}", histogram.sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{route=\"{route}\"}} {}", histogram.count);
+        }
+
+        out.push_str("# HELP http_active_connections Connections open right now.\n");
+        out.push_str("# TYPE http_active_connections gauge\n");
+        let _ = writeln!(out, "http_active_connections {}", server::active_connections());
+
+        if let Some(pool) = &self.pool {
+            let stats = pool.stats();
+            out.push_str("# HELP threadpool_workers Worker threads, by whether they're running a job.\n");
+            out.push_str("# TYPE threadpool_workers gauge\n");
+            let _ = writeln!(out, "threadpool_workers{{state=\"busy\"}} {}", stats.busy);
+            let _ = writeln!(out, "threadpool_workers{{state=\"idle\"}} {}", stats.idle());
+            out.push_str("# HELP threadpool_queue_depth Jobs waiting for a free worker.\n");
+            out.push_str("# TYPE threadpool_queue_depth gauge\n");
+            let _ = writeln!(out, "threadpool_queue_depth {}", stats.queued);
+            out.push_str("# HELP threadpool_jobs_dropped_total Queued jobs dropped to make room for newer ones.\n");
+            out.push_str("# TYPE threadpool_jobs_dropped_total counter\n");
+            let _ = writeln!(out, "threadpool_jobs_dropped_total {}", stats.dropped);
+            out.push_str("# HELP threadpool_timer_runs_skipped_total Timer runs skipped because the queue was full.\n");
+            out.push_str("# TYPE threadpool_timer_runs_skipped_total counter\n");
+            let _ = writeln!(out, "threadpool_timer_runs_skipped_total {}", stats.skipped);
+        }
+
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        if request.method == "GET" && request.path == self.path {
+            return Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(self.render());
+        }
+
+        let start = Instant::now();
+        let response = next.run(request);
+        self.record(self.route_of(&request.path), response.status, start.elapsed());
+
+        response
+    }
+}
+
+// Label values are quoted, so quotes, backslashes and line breaks in them must be escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{Handler, Pipeline};
+
+    fn get(path: &str) -> Request {
+        Request::parse(&mut format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn labels_by_route_not_path() {
+        let metrics = Metrics::new().route("/").route("/api").route("/api/admin");
+
+        assert_eq!(metrics.route_of("/"), "/");
+        assert_eq!(metrics.route_of("/?page=2"), "/");
+        assert_eq!(metrics.route_of("/api/users"), "/api");
+        assert_eq!(metrics.route_of("/api/admin/1"), "/api/admin");
+        assert_eq!(metrics.route_of("/apiary"), "other");
+        assert_eq!(metrics.route_of("/made-up"), "other");
+    }
+
+    #[test]
+    fn counts_requests_and_serves_them() {
+        let app = Pipeline::new(|request: &Request| Response::new(if request.path == "/" { 200 } else { 404 }))
+            .with(Metrics::new().route("/"));
+
+        app.handle(&mut get("/"));
+        app.handle(&mut get("/"));
+        app.handle(&mut get("/nope"));
+        let text = String::from_utf8(app.handle(&mut get("/metrics")).body).unwrap();
+
+        assert!(text.contains("http_requests_total{route=\"/\",status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{route=\"other\",status=\"404\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{route=\"/\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_count{route=\"other\"} 1\n"));
+    }
+}
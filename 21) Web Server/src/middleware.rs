@@ -0,0 +1,249 @@
+/* Summary:
+Middleware wraps a handler so cross-cutting concerns (request IDs, timing, CORS, ...) can be
+added to every route without touching handle_connection or the handlers themselves.
+
+A Pipeline is a list of middleware around one handler. A request passes through each middleware
+in the order they were added. Each one can change the request, call next.run() to pass it
+further in, and then change the response on the way back out.
+
+    request -> RequestId -> Timing -> handler
+    response <- RequestId <- Timing <-
+
+Some responses never come out of a pipeline: a request that couldn't be parsed, one the pool
+was too busy to take, and a handler that panicked are all answered by the server itself. The
+server still tells the app about them, with unhandled(), so e.g. the access log can list them.
+*/
+
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::http::{Request, Response};
+
+/* Anything that turns a request into a response.
+Closures taking a &Request implement it automatically, so most handlers are just closures or fns.
+*/
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &mut Request) -> Response;
+
+    // Called for a response the server sent without handle() returning one. Ignored by default.
+    fn unhandled(&self, _response: &Unhandled<'_>) {}
+}
+
+// A response the server sent by itself, and what's known about the request it answered.
+pub struct Unhandled<'a> {
+    pub remote_addr: Option<SocketAddr>,
+    pub request: Option<&'a Request>, // None if it couldn't be read, or hadn't been yet
+    pub status: u16,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request) -> Response {
+        self(request)
+    }
+}
+
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+
+    // See Handler::unhandled().
+    fn unhandled(&self, _response: &Unhandled<'_>) {}
+}
+
+// The rest of the pipeline, from the point of view of one middleware.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+pub struct Pipeline {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Pipeline {
+    pub fn new(handler: impl Handler + 'static) -> Self {
+        Pipeline {
+            middleware: Vec::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    // Middleware added first is the outermost, so it sees the request first and the response last.
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+// A Pipeline is itself a Handler, so pipelines can be nested.
+impl Handler for Pipeline {
+    fn handle(&self, request: &mut Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: self.handler.as_ref(),
+        }
+        .run(request)
+    }
+
+    // Every middleware hears about it, as does the handler, which may be another pipeline.
+    fn unhandled(&self, response: &Unhandled<'_>) {
+        for middleware in &self.middleware {
+            middleware.unhandled(response);
+        }
+        self.handler.unhandled(response);
+    }
+}
+
+/* Gives every request an X-Request-Id header, and echoes it on the response.
+If the client (or a proxy in front of us) already sent one, that ID is kept.
+*/
+pub struct RequestId {
+    next_id: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        RequestId {
+            next_id: AtomicU64::new(1),
+        }
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let id = match request.header("X-Request-Id") {
+            Some(id) => id.to_string(),
+            None => self.next_id.fetch_add(1, Ordering::Relaxed).to_string(),
+        };
+        request.set_header("X-Request-Id", id.as_str());
+
+        next.run(request).with_header("X-Request-Id", id)
+    }
+}
+
+// Adds an X-Response-Time header with how long the rest of the pipeline took, in milliseconds.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let start = Instant::now();
+        let response = next.run(request);
+        let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+
+        response.with_header("X-Response-Time", format!("{elapsed:.3}ms"))
+    }
+}
+
+/* Cross-Origin Resource Sharing. Lets pages served from other origins call this server.
+Preflight OPTIONS requests are answered here and never reach the handler.
+*/
+pub struct Cors {
+    allow_origin: String,
+    allow_methods: String,
+    allow_headers: String,
+}
+
+impl Cors {
+    // Allow any origin.
+    pub fn any() -> Self {
+        Cors::new("*")
+    }
+
+    pub fn new(allow_origin: &str) -> Self {
+        Cors {
+            allow_origin: allow_origin.to_string(),
+            allow_methods: "GET, POST, PUT, DELETE, OPTIONS".to_string(),
+            allow_headers: "Content-Type".to_string(),
+        }
+    }
+
+    pub fn allow_methods(mut self, methods: &str) -> Self {
+        self.allow_methods = methods.to_string();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &str) -> Self {
+        self.allow_headers = headers.to_string();
+        self
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let response = if request.method == "OPTIONS" {
+            Response::new(204)
+                .with_header("Access-Control-Allow-Methods", self.allow_methods.as_str())
+                .with_header("Access-Control-Allow-Headers", self.allow_headers.as_str())
+        } else {
+            next.run(request)
+        };
+
+        response.with_header("Access-Control-Allow-Origin", self.allow_origin.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn middleware_wraps_handler() {
+        let app = Pipeline::new(|request: &Request| {
+            // RequestId runs before the handler, so the header is already set.
+            Response::new(200).with_body(request.header("X-Request-Id").unwrap())
+        })
+        .with(RequestId::new())
+        .with(Timing);
+
+        let response = app.handle(&mut request("GET", "/"));
+
+        assert_eq!(response.body, b"1");
+        assert_eq!(response.header("X-Request-Id"), Some("1"));
+        assert!(response.header("X-Response-Time").is_some());
+    }
+
+    #[test]
+    fn cors_answers_preflight() {
+        let app = Pipeline::new(|_: &Request| panic!("preflight should not reach the handler"))
+            .with(Cors::new("https://example.com"));
+
+        let response = app.handle(&mut request("OPTIONS", "/"));
+
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("https://example.com"));
+    }
+}
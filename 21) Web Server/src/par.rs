@@ -0,0 +1,164 @@
+/* Summary:
+Parallel versions of the usual iterator pipelines, run on a ThreadPool. Where the sequential code
+would be
+    let total: u64 = numbers.iter().map(|n| n * n).sum();
+on the pool it's
+    let squares = pool.par_map(&numbers, |n| n * n);
+    let total = pool.par_reduce(squares, || 0, |a, b| a + b);
+
+The items are split into chunks, a few per worker, and each chunk is one job. Jobs are run in a
+ThreadPool::scope(), so the closures can borrow from the caller like their sequential versions.
+Results come back in the same order as the items, whichever job finished first. Every chunk is
+run, on the caller's thread if the pool's queue is full, so none of them can be missing. If one
+were, that's a bug, and it panics rather than give a short answer.
+
+Only the closures run in parallel. The iterator itself is consumed on the caller's thread, so a
+filter() or map() chained onto it before it's passed in is still sequential. It's the expensive
+step that belongs in the closure.
+*/
+
+use std::iter;
+
+use crate::ThreadPool;
+
+// How many chunks each worker gets, so a worker that's done early can take another.
+const CHUNKS_PER_WORKER: usize = 4;
+
+impl ThreadPool {
+    // f(item) for every item, in parallel. The results are in the same order as the items.
+    pub fn par_map<T, U, F>(&self, items: impl IntoIterator<Item = T>, f: F) -> Vec<U>
+    where
+        T: Send,
+        U: Send,
+        F: Fn(T) -> U + Sync,
+    {
+        let chunks = self.chunks(items);
+        let mut results: Vec<Option<Vec<U>>> = chunks.iter().map(|_| None).collect();
+
+        self.scope(|s| {
+            for (chunk, result) in chunks.into_iter().zip(&mut results) {
+                let f = &f;
+                s.spawn(move || *result = Some(chunk.into_iter().map(f).collect()));
+            }
+        });
+
+        results.into_iter().flat_map(every_chunk).collect()
+    }
+
+    // f(item) for every item, in parallel and in no particular order.
+    pub fn par_for_each<T, F>(&self, items: impl IntoIterator<Item = T>, f: F)
+    where
+        T: Send,
+        F: Fn(T) + Sync,
+    {
+        self.scope(|s| {
+            for chunk in self.chunks(items) {
+                let f = &f;
+                s.spawn(move || chunk.into_iter().for_each(f));
+            }
+        });
+    }
+
+    /* Combine all the items into one with `op`, in parallel: each chunk is combined on its own,
+    starting from identity(), then the chunks' results are combined in order. `op` has to be
+    associative, e.g. adding or concatenating, but needn't be commutative. No items gives identity().
+    */
+    pub fn par_reduce<T, I, F>(&self, items: impl IntoIterator<Item = T>, identity: I, op: F) -> T
+    where
+        T: Send,
+        I: Fn() -> T + Sync,
+        F: Fn(T, T) -> T + Sync,
+    {
+        let chunks = self.chunks(items);
+        let mut results: Vec<Option<T>> = chunks.iter().map(|_| None).collect();
+
+        self.scope(|s| {
+            for (chunk, result) in chunks.into_iter().zip(&mut results) {
+                let (identity, op) = (&identity, &op);
+                s.spawn(move || *result = Some(chunk.into_iter().fold(identity(), op)));
+            }
+        });
+
+        results.into_iter().map(every_chunk).fold(identity(), op)
+    }
+
+    // The items, split into a few chunks per worker.
+    fn chunks<T>(&self, items: impl IntoIterator<Item = T>) -> Vec<Vec<T>> {
+        let items: Vec<T> = items.into_iter().collect();
+        // A dynamic pool may have no workers right now, but it'll start one for the first job.
+        let chunks = self.stats().workers.max(1) * CHUNKS_PER_WORKER;
+        let chunk_size = items.len().div_ceil(chunks).max(1);
+
+        let mut items = items.into_iter();
+        iter::from_fn(|| {
+            let chunk: Vec<T> = items.by_ref().take(chunk_size).collect();
+            (!chunk.is_empty()).then_some(chunk)
+        })
+        .collect()
+    }
+}
+
+// A chunk's result, which scope() made sure is there.
+fn every_chunk<T>(result: Option<T>) -> T {
+    result.expect("a chunk wasn't run")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Overflow;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn map_keeps_the_order() {
+        let pool = ThreadPool::new(4);
+        let numbers: Vec<u64> = (0..10_000).collect();
+
+        let doubled = pool.par_map(&numbers, |n| n * 2);
+        assert_eq!(doubled, numbers.iter().map(|n| n * 2).collect::<Vec<_>>());
+
+        let words = ["one", "three", "five"];
+        assert_eq!(pool.par_map(words, str::len), [3, 5, 4]);
+        assert!(pool.par_map(Vec::<u64>::new(), |n| n).is_empty());
+    }
+
+    #[test]
+    fn for_each_visits_every_item() {
+        let pool = ThreadPool::new(4);
+        let total = AtomicU64::new(0);
+
+        pool.par_for_each(1..=1000, |n| {
+            total.fetch_add(n, Ordering::Relaxed);
+        });
+        assert_eq!(total.load(Ordering::Relaxed), 500_500);
+    }
+
+    #[test]
+    fn reduce_combines_chunks_in_order() {
+        let pool = ThreadPool::new(4);
+
+        assert_eq!(pool.par_reduce(1..=1000u64, || 0, |a, b| a + b), 500_500);
+        // Concatenating isn't commutative, so this only works if the order is kept.
+        let letters = ('a'..='z').map(String::from);
+        let alphabet = pool.par_reduce(letters, String::new, |a, b| a + &b);
+        assert_eq!(alphabet, "abcdefghijklmnopqrstuvwxyz");
+        assert_eq!(pool.par_reduce(iter::empty::<u64>(), || 7, |a, b| a + b), 7);
+    }
+
+    #[test]
+    fn full_pools_still_give_every_result() {
+        // Chunks that don't fit in the queue are run by the caller, whatever the overflow.
+        for overflow in [Overflow::Reject, Overflow::DropOldest, Overflow::Block] {
+            let pool = ThreadPool::bounded(2, 1, overflow);
+            let numbers: Vec<u64> = (0..10_000).collect();
+
+            assert_eq!(pool.par_map(&numbers, |n| n * 2), numbers.iter().map(|n| n * 2).collect::<Vec<_>>());
+            assert_eq!(pool.par_reduce(numbers.iter().copied(), || 0, |a, b| a + b), 49_995_000);
+            let total = AtomicU64::new(0);
+            pool.par_for_each(&numbers, |n| {
+                total.fetch_add(*n, Ordering::Relaxed);
+            });
+            assert_eq!(total.load(Ordering::Relaxed), 49_995_000);
+        }
+    }
+}
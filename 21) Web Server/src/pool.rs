@@ -0,0 +1,1864 @@
+/* Summary:
+The thread pool that runs the server's connections, and any other jobs given to it.
+
+Every worker has its own queue of jobs (a deque), and there's one shared queue, the injector,
+for each Priority, that execute() puts new jobs into. A worker looks for its next job:
+1. in the High priority injector, one job at a time,
+2. in its own deque,
+3. in the Normal priority injector, moving a whole batch of jobs into its own deque at once,
+4. in the other workers' deques, stealing from them,
+5. in the Low priority injector, one job at a time.
+Only when all of them are empty does it go to sleep, until execute() wakes it.
+High and Low priority jobs never go into a deque, where they'd have to wait for the jobs ahead of
+them. So a High priority job, e.g. a health check, is the next job any worker takes, however many
+Normal ones are waiting. Low priority jobs only run when there's nothing else to do.
+
+The first version of the pool had one channel for all the jobs, behind one mutex that every
+worker locked to take each job. With lots of tiny jobs the workers spent more time waiting for
+that lock than running jobs. Now a worker takes most of its jobs from its own deque, which costs
+almost nothing, and only goes to the injector once per batch. An idle worker can steal from a
+busy one, so jobs stuck behind a slow one still get run.
+$ cargo bench --bench tiny_jobs
+
+ThreadPool::new() queues as many jobs as it's given. A server that's sent connections faster
+than it can serve them would queue them until it ran out of memory. ThreadPool::bounded() only
+queues so many, and then does one of these with the next job, see Overflow:
+- Block: execute() waits until a worker takes a job off the queue.
+- Reject: execute() returns the job in an Err, for the caller to deal with.
+- DropOldest: the job that's been waiting longest is thrown away to make room.
+- CallerRuns: execute() runs the job itself, on the caller's thread. That slows the caller down,
+  which is the point.
+
+A job that panics doesn't take its worker down with it: the panic is caught, passed to the hook
+given to Builder::on_panic(), and the worker goes on to the next job. Should a worker die anyway, e.g.
+because the hook panicked too, it's replaced, so a long-running server never slowly loses its
+workers. The pool prints nothing about either. Rust's own panic hook has already reported the
+panic on stderr, unless the program replaced it with std::panic::set_hook().
+
+ThreadPool::dynamic() makes a pool whose size changes with the load. It starts with `min`
+workers, and adds more, up to `max`, whenever jobs are waiting and no worker is free to take
+them. A worker beyond the first `min` that's had nothing to do for `keep_alive` goes away again.
+resize() changes `min` and `max` while the pool is running.
+
+ThreadPool::builder() has all of the above as settings, plus some for the worker threads: their
+names, which show up in debuggers, panic messages and `top -H`, and their stack size.
+The panic hook is one of them, so it's in place before the first job can panic.
+
+    let pool = ThreadPool::builder().workers(4).max_workers(16).thread_name("hello-worker").build();
+
+Jobs given to execute() have to be 'static, since nothing stops them outliving the function that
+sent them. Jobs spawned inside scope() don't: scope() waits for them all before it returns, so
+they can borrow from the caller's stack, like std::thread::scope():
+
+    let mut counts = [0; 4];
+    pool.scope(|s| {
+        for (count, chunk) in counts.iter_mut().zip(text.chunks(1000)) {
+            s.spawn(move || *count = chunk.split_whitespace().count());
+        }
+    });
+
+execute_after() and execute_every() run a job later, once or over and over. One timer thread per
+pool keeps the timers in a heap, soonest first, and sleeps until the first is due. Then it sends
+that timer's job to the workers like execute() would, so a slow job doesn't hold up other timers.
+
+    let cleanup = pool.execute_every(Duration::from_secs(60), move || cache.remove_expired());
+    ...
+    cleanup.cancel();
+
+Dropping the pool waits for every job that's been sent to it. To not wait forever, there's
+shutdown_graceful(timeout), which gives up on the jobs that haven't run by then, and shutdown_now(),
+which only waits for the jobs that are already running, and gives the rest back. Both say how many
+jobs were finished and how many weren't, for the caller to log:
+
+    let report = pool.shutdown_graceful(Duration::from_secs(30));
+    println!("{} jobs finished, {} abandoned", report.completed, report.abandoned);
+*/
+
+use std::{
+    any::Any,
+    cmp,
+    collections::BinaryHeap,
+    error::Error,
+    fmt, hint, iter,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+
+// How many times an idle worker looks for a job before going to sleep.
+const SPIN_ROUNDS: u32 = 64;
+
+// How long a worker beyond a dynamic pool's minimum waits for a job before leaving, by default.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+}
+
+// What a bounded pool does with a job that doesn't fit in its queue. See the summary at the top.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Block,
+    Reject,
+    DropOldest, // The oldest of the lowest priority jobs.
+    CallerRuns,
+}
+
+/* Which queued jobs run first. Jobs of the same priority run in the order they were sent.
+A steady stream of higher priority jobs can keep lower ones waiting indefinitely.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/* The job execute() or spawn() couldn't queue, given back. Only a pool made with
+ThreadPool::bounded(..., Overflow::Reject) turns jobs away.
+*/
+pub struct Rejected<F>(pub F);
+
+impl<F> fmt::Debug for Rejected<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Rejected(..)")
+    }
+}
+
+impl<F> fmt::Display for Rejected<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the thread pool's queue is full")
+    }
+}
+
+impl<F> Error for Rejected<F> {}
+
+// What the hook given to Builder::on_panic() is told about a job that panicked.
+pub struct JobPanic<'a> {
+    pub worker: usize, // The id of the worker that ran the job.
+    pub payload: &'a (dyn Any + Send),
+}
+
+impl JobPanic<'_> {
+    // The panic's message. panic!() makes the payload a &str or a String, and that covers nearly all of them.
+    pub fn message(&self) -> &str {
+        if let Some(message) = self.payload.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = self.payload.downcast_ref::<String>() {
+            message
+        } else {
+            "Box<dyn Any>"
+        }
+    }
+}
+
+type PanicHook = Arc<dyn Fn(&JobPanic) + Send + Sync>;
+
+/* Sets up a pool, see ThreadPool::builder(). Anything not set is the same as for ThreadPool::new():
+one worker per CPU, no limit on the queue, and unnamed threads with the default stack size.
+*/
+#[derive(Clone)]
+pub struct Builder {
+    min: usize,
+    max: Option<usize>, // None for the same as `min`.
+    keep_alive: Duration,
+    capacity: Option<usize>, // None for no limit.
+    overflow: Overflow,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    panic_hook: Option<PanicHook>,
+}
+
+// The hook is a closure, which can't be printed.
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("keep_alive", &self.keep_alive)
+            .field("capacity", &self.capacity)
+            .field("overflow", &self.overflow)
+            .field("thread_name", &self.thread_name)
+            .field("stack_size", &self.stack_size)
+            .field("panic_hook", &self.panic_hook.is_some())
+            .finish()
+    }
+}
+
+// What the pool and all its workers share.
+struct Shared {
+    // Here rather than in the ThreadPool, so a dying worker can put its replacement in its place.
+    workers: Mutex<Vec<Worker>>,
+    injectors: [Injector<Job>; 3], // One for each Priority, highest first.
+    // Handles to each worker's deque, by worker id. The id of a worker that left is None until reused.
+    stealers: RwLock<Vec<Option<Stealer<Job>>>>,
+    counters: Arc<Counters>,
+    min: AtomicUsize,
+    max: AtomicUsize,
+    keep_alive: Duration,
+    // Idle workers wait on `wake`. `sleep` only exists to go with it.
+    sleep: Mutex<()>,
+    wake: Condvar,
+    sleeping: AtomicUsize,
+    shutdown: AtomicBool,
+    capacity: Option<usize>, // None for no limit.
+    overflow: Overflow,
+    // Callers blocked by a full queue wait on `space`, the same way idle workers wait on `wake`.
+    full: Mutex<()>,
+    space: Condvar,
+    blocked: AtomicUsize,
+    panic_hook: Option<PanicHook>,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    // The timer thread waits on `timer_set` for the next timer, or for a sooner one to be set.
+    timers: Mutex<Timers>,
+    timer_set: Condvar,
+    // Every job that's been queued, and every one that's been run. Jobs dropped to make room are
+    // counted in Counters::dropped instead. A timer's job that was turned away at shutdown counts
+    // as sent, so it's reported as abandoned.
+    sent: AtomicUsize,
+    done: AtomicUsize,
+    // Worker threads that haven't ended yet. `all_gone` is notified when it gets to 0.
+    live: Mutex<usize>,
+    all_gone: Condvar,
+}
+
+// What happened to the jobs that were left when the pool was shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub completed: usize, // Jobs that were queued or running, and finished before the pool stopped.
+    pub abandoned: usize, // Jobs that never ran, or were still running when the pool gave up on them.
+}
+
+// The timers not yet due, for the timer thread. See ThreadPool::execute_after().
+#[derive(Default)]
+struct Timers {
+    heap: BinaryHeap<Timer>, // The soonest on top.
+    next_seq: u64,
+    thread: Option<thread::JoinHandle<()>>, // Started when the first timer's set.
+    stopped: bool,                          // The pool's being dropped.
+}
+
+struct Timer {
+    due: Instant,
+    seq: u64, // Timers due at the same time go off in the order they were set.
+    cancelled: Arc<AtomicBool>,
+    task: Task,
+}
+
+enum Task {
+    Once(Job),
+    Every(Duration, Arc<dyn Fn() + Send + Sync>),
+}
+
+// BinaryHeap keeps the greatest on top, so timers compare the other way round: sooner is greater.
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Timer {}
+
+// Returned by execute_after() and execute_every(). Dropping it doesn't cancel the timer.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    // Stop the job from running (again). A run that's already started isn't interrupted.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// What to do with a job that's about to be executed.
+enum Admit {
+    Queue,
+    Reject,
+    RunHere,
+}
+
+// Why a sleeping worker woke up.
+enum Wake {
+    Work,
+    Retire, // It isn't needed any more. See Shared::sleep().
+    Shutdown,
+}
+
+// What the workers are up to, kept up to date by the pool and the workers themselves.
+#[derive(Default)]
+struct Counters {
+    size: AtomicUsize,    // Workers there are, or are about to be.
+    busy: AtomicUsize,    // Workers running a job.
+    queued: AtomicUsize,  // Jobs sent but not yet picked up by a worker.
+    dropped: AtomicUsize, // Jobs dropped from the queue to make room for newer ones.
+    skipped: AtomicUsize, // Timer runs that found the queue full, and never ran.
+}
+
+// A snapshot of the pool, e.g. for the /metrics endpoint. See ThreadPool::stats().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub busy: usize,
+    pub queued: usize,
+    pub dropped: usize, // In all, since the pool started. Only Overflow::DropOldest drops jobs.
+    pub skipped: usize, // Timer runs that found the queue full, in all. See ThreadPool::execute_after().
+}
+
+impl PoolStats {
+    pub fn idle(&self) -> usize {
+        self.workers.saturating_sub(self.busy)
+    }
+}
+
+/* Reads the pool's stats without keeping the pool alive, so it can be held by the app, which the
+pool's own workers run. Holding the pool itself there could leave a worker dropping the pool,
+and waiting for itself to finish.
+*/
+#[derive(Clone)]
+pub struct PoolMonitor {
+    counters: Arc<Counters>,
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            workers: self.counters.size.load(Ordering::Relaxed),
+            busy: self.counters.busy.load(Ordering::Relaxed),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            skipped: self.counters.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Builder {
+    // How many workers the pool has, or starts with if max_workers() is more.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.min = workers;
+        self
+    }
+
+    // Add workers when all of them are busy, up to this many. See ThreadPool::dynamic().
+    pub fn max_workers(mut self, max: usize) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    // How long a worker beyond the first `workers` waits for a job before leaving. 60s by default.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Queue at most `capacity` jobs, see ThreadPool::bounded().
+    pub fn queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.capacity = Some(capacity);
+        self.overflow = overflow;
+        self
+    }
+
+    // Name the worker threads "{prefix}-{id}". Linux only shows the first 15 bytes of a name.
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> Self {
+        self.thread_name = Some(prefix.into());
+        self
+    }
+
+    // The size of each worker thread's stack, in bytes.
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /* Call this with every panic from a job run by execute(), e.g. to log it or count it. It's
+    called on the worker that ran the job, just after the panic. Jobs run by spawn() don't need
+    it, their panics come out of JobHandle::join().
+    */
+    pub fn on_panic(mut self, hook: impl Fn(&JobPanic) + Send + Sync + 'static) -> Self {
+        self.panic_hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn build(self) -> ThreadPool {
+        let max = self.max.unwrap_or(self.min);
+        assert!(max > 0 && self.min <= max);
+        assert!(self.capacity != Some(0));
+
+        let shared = Arc::new(Shared {
+            workers: Mutex::new(Vec::with_capacity(max)),
+            injectors: [Injector::new(), Injector::new(), Injector::new()],
+            stealers: RwLock::new(Vec::with_capacity(max)),
+            counters: Arc::new(Counters::default()),
+            min: AtomicUsize::new(self.min),
+            max: AtomicUsize::new(max),
+            keep_alive: self.keep_alive,
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            sleeping: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            capacity: self.capacity,
+            overflow: self.overflow,
+            full: Mutex::new(()),
+            space: Condvar::new(),
+            blocked: AtomicUsize::new(0),
+            panic_hook: self.panic_hook,
+            thread_name: self.thread_name,
+            stack_size: self.stack_size,
+            timers: Mutex::new(Timers::default()),
+            timer_set: Condvar::new(),
+            sent: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            live: Mutex::new(0),
+            all_gone: Condvar::new(),
+        });
+
+        for _ in 0..self.min {
+            shared.counters.size.fetch_add(1, Ordering::SeqCst);
+            shared.add_worker();
+        }
+        ThreadPool { shared }
+    }
+}
+
+impl ThreadPool {
+    // A pool of `size` workers, with no limit on how many jobs can wait for them.
+    pub fn new(size: usize) -> Self {
+        ThreadPool::builder().workers(size).build()
+    }
+
+    /* A pool of `size` workers that queues at most `capacity` jobs. Jobs that are running
+    don't count. What happens to the ones that don't fit is up to `overflow`.
+    */
+    pub fn bounded(size: usize, capacity: usize, overflow: Overflow) -> Self {
+        ThreadPool::builder().workers(size).queue(capacity, overflow).build()
+    }
+
+    /* A pool of between `min` and `max` workers, depending on how busy it is. Workers beyond
+    `min` leave after `keep_alive` with nothing to do. `min` may be 0, for a pool that has no
+    threads at all while it's not used.
+    */
+    pub fn dynamic(min: usize, max: usize, keep_alive: Duration) -> Self {
+        ThreadPool::builder().workers(min).max_workers(max).keep_alive(keep_alive).build()
+    }
+
+    pub fn builder() -> Builder {
+        Builder {
+            min: thread::available_parallelism().map_or(1, usize::from),
+            max: None,
+            keep_alive: KEEP_ALIVE,
+            capacity: None,
+            overflow: Overflow::Block,
+            thread_name: None,
+            stack_size: None,
+            panic_hook: None,
+        }
+    }
+
+    /* Change the bounds of the pool's size. Workers are added at once to make up `min`.
+    Any beyond `max` finish the job they're running, if any, and then leave.
+    */
+    pub fn resize(&self, min: usize, max: usize) {
+        assert!(max > 0 && min <= max);
+        self.shared.min.store(min, Ordering::SeqCst);
+        self.shared.max.store(max, Ordering::SeqCst);
+
+        while self.shared.grow(min) {
+            self.shared.add_worker();
+        }
+        // Idle workers only check whether they're too many when they wake.
+        self.shared.wake_all();
+    }
+
+    // How many workers there are, how many are busy, and how many jobs are waiting for one.
+    pub fn stats(&self) -> PoolStats {
+        self.monitor().stats()
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            counters: Arc::clone(&self.shared.counters),
+        }
+    }
+
+    /* Run the job on one of the workers. Err only comes from a full pool whose overflow is
+    Overflow::Reject, and gives the job back. Other pools always return Ok.
+    */
+    pub fn execute<F>(&self, f: F) -> Result<(), Rejected<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, f)
+    }
+
+    /* Like execute(), but the job goes ahead of any queued jobs of a lower priority. It still
+    has to wait for a free worker, it doesn't interrupt a job that's running. Nor does it get a
+    place in a bounded pool's queue that's full of lower priority jobs, unless the overflow
+    is Overflow::DropOldest.
+    */
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), Rejected<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.shared.admit(self.shared.overflow) {
+            Admit::Queue => self.shared.push(Box::new(f), priority),
+            Admit::Reject => return Err(Rejected(f)),
+            Admit::RunHere => f(),
+        }
+        Ok(())
+    }
+
+    /* Like execute(), but it never waits for room in the queue, or runs the job itself: when the
+    queue is full it returns Err, as if the overflow were Overflow::Reject. Only DropOldest still
+    makes room. It's for a thread that mustn't be held up by a job, like the event loop's.
+    */
+    pub fn try_execute<F>(&self, f: F) -> Result<(), Rejected<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let overflow = match self.shared.overflow {
+            Overflow::DropOldest => Overflow::DropOldest,
+            _ => Overflow::Reject,
+        };
+        match self.shared.admit(overflow) {
+            Admit::Queue => self.shared.push(Box::new(f), Priority::Normal),
+            _ => return Err(Rejected(f)),
+        }
+        Ok(())
+    }
+
+    /* Like execute(), but the job can return something. The handle's join() waits for the job to
+    finish and gives back what it returned, like thread::spawn() does:
+
+        let handle = pool.spawn(|| 6 * 7);
+        assert_eq!(handle.join().unwrap(), 42);
+
+    A panic in the job is caught, and join() returns it as an Err instead. The worker lives on.
+    */
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobHandle<T>, Rejected<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let admit = self.shared.admit(self.shared.overflow);
+        if let Admit::Reject = admit {
+            return Err(Rejected(f));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let finished = Arc::new(AtomicBool::new(false));
+        let done = Arc::clone(&finished);
+        let job = move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            done.store(true, Ordering::Release);
+            // Nobody may be waiting for the result, if the handle was dropped. That's fine.
+            let _ = sender.send(result);
+        };
+
+        match admit {
+            Admit::RunHere => job(),
+            _ => self.shared.push(Box::new(job), Priority::Normal),
+        }
+        Ok(JobHandle { receiver, finished })
+    }
+}
+
+// Returned by ThreadPool::spawn(). Dropping it doesn't cancel the job, the result is just thrown away.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl<T> JobHandle<T> {
+    /* Wait for the job and return its result. Err holds the panic's payload if the job panicked,
+    the same as JoinHandle::join(). It's usually a &str or String with the panic message.
+    */
+    pub fn join(self) -> thread::Result<T> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            // The job was dropped without running, e.g. the pool shut down first.
+            Err(_) => Err(Box::new("job was dropped before it ran")),
+        }
+    }
+
+    // Whether the job is done, so join() won't block.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+impl ThreadPool {
+    /* Run `f`, which may spawn jobs on the pool that borrow anything that outlives the scope.
+    All of them have finished by the time scope() returns, so the borrows can't outlive what they
+    borrow. It returns whatever `f` returns.
+
+    If `f` or any of the jobs panicked, scope() panics too, once all the jobs are done, with the
+    first panic's payload. A job's panic doesn't go to the Builder::on_panic() hook. So does a job
+    that never ran, because another one pushed it out of a full queue (Overflow::DropOldest).
+
+    Beware of calling scope() from a job on the same pool: if every worker is waiting for its own
+    scope's jobs, there's no worker left to run them.
+    */
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState::default()),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // Even if `f` panics, the jobs it spawned still have its borrows, so they're waited for first.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+
+        let panic = scope.state.panic.lock().unwrap_or_else(PoisonError::into_inner).take();
+        match (result, panic) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(result), None) => result,
+        }
+    }
+}
+
+/* What ThreadPool::scope() passes to its function, to spawn jobs with. 'scope is how long the
+scope lasts, and 'env is how long anything the jobs borrow lasts, at least as long as the scope.
+The markers make both lifetimes invariant, so neither can be stretched or shrunk to fit a borrow.
+*/
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// How many of a scope's jobs haven't finished yet, and the first panic among them.
+#[derive(Default)]
+struct ScopeState {
+    running: Mutex<usize>,
+    finished: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /* Run a job on the pool. It may borrow anything that lives longer than the scope.
+    A job the pool has no room for is run right away on this thread instead, whatever the pool's
+    Overflow. It has to run before the scope ends either way, so it never waits for room, or pushes
+    another job out of the queue to make some.
+    */
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *lock(&self.state.running) += 1;
+        // Dropped after the job has run, or with the job if the pool throws it away unrun.
+        let running = Running {
+            state: Arc::clone(&self.state),
+            ran: false,
+        };
+
+        // A tuple drops its fields in order, so `f` and its borrows go before `running` says they're done.
+        let job = (f, running);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let (f, mut running) = job;
+            running.ran = true;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                lock(&running.state.panic).get_or_insert(payload);
+            }
+        });
+        /* The pool only takes 'static jobs, because it has no idea when they'll run. This one's
+        lifetime is hidden from it, which is safe because scope() doesn't return (and so nothing
+        the job borrows can go away) until the job has finished or been dropped.
+        */
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        match self.pool.shared.admit(Overflow::CallerRuns) {
+            Admit::Queue => self.pool.shared.push(job, Priority::Normal),
+            Admit::Reject | Admit::RunHere => job(),
+        }
+    }
+}
+
+impl ScopeState {
+    fn wait(&self) {
+        let mut running = lock(&self.running);
+        while *running > 0 {
+            running = self.finished.wait(running).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+// One of a scope's jobs that hasn't finished yet, and whether it's started.
+struct Running {
+    state: Arc<ScopeState>,
+    ran: bool,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        // The job was thrown away, so the scope's work isn't all done. Don't let that pass silently.
+        if !self.ran {
+            lock(&self.state.panic).get_or_insert(Box::new("a scoped job was dropped before it ran"));
+        }
+
+        let mut running = lock(&self.state.running);
+        *running -= 1;
+        if *running == 0 {
+            self.state.finished.notify_all();
+        }
+    }
+}
+
+impl ThreadPool {
+    /* Run the job on one of the workers once `delay` has passed, unless the timer's cancelled
+    first. A pool that's dropped first drops the job too.
+
+    The job is sent to the workers like execute() would, at Normal priority. If a bounded pool's
+    queue is full then, it's skipped, unless the overflow is Overflow::Block or DropOldest. Block
+    makes the timer thread wait for room, so other timers may go off late. Skipped runs are counted
+    in stats(), so a periodic job that keeps being skipped can be noticed.
+    */
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.set_timer(delay, Task::Once(Box::new(f)))
+    }
+
+    /* Run the job every `interval`, starting one interval from now, until the timer's cancelled
+    or the pool is dropped. Like execute_after(), a run that finds the queue full is skipped.
+
+    Runs are due at fixed times, however long each one takes. So a slow run doesn't hold up the
+    next, and `f` may be running on two workers at once. A run that's late because the timer
+    thread was held up doesn't make the next one late. Runs missed altogether are skipped rather
+    than made up for all at once.
+    */
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero());
+        self.shared.set_timer(interval, Task::Every(interval, Arc::new(f)))
+    }
+}
+
+impl ThreadPool {
+    /* Stop the pool, letting the workers run the jobs that are queued for up to `timeout`.
+    If they're all done by then, this returns as soon as they are, like dropping the pool. If not,
+    the jobs still queued are dropped, and the workers are left to finish the ones they're running
+    on their own, in the background.
+    */
+    pub fn shutdown_graceful(self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        let done_before = self.shared.done.load(Ordering::SeqCst);
+        // The workers first, so a timer thread that's waiting for room in the queue gives up
+        // instead of holding up stop_timers(). See admit().
+        self.shared.stop_workers();
+        self.shared.stop_timers();
+
+        let live = lock(&self.shared.live);
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let (live, _) = self.shared.all_gone.wait_timeout_while(live, wait, |live| *live > 0).unwrap_or_else(PoisonError::into_inner);
+        let finished = *live == 0;
+        drop(live);
+
+        if finished {
+            self.shared.join_workers();
+        } else {
+            // Dropping the JoinHandles leaves the threads running without anyone waiting for them.
+            self.shared.take_queued();
+            lock(&self.shared.workers).clear();
+        }
+        self.shared.report(done_before)
+    }
+
+    /* Stop the pool without running any more jobs. The ones that are running are finished, and
+    the ones still queued are given back, to run some other way or just drop.
+    */
+    pub fn shutdown_now(self) -> (ShutdownReport, Vec<Job>) {
+        self.shared.stop_timers();
+        let done_before = self.shared.done.load(Ordering::SeqCst);
+        // Taken before the workers are woken up to finish the queue.
+        let jobs = self.shared.take_queued();
+        self.shared.stop_workers();
+        self.shared.join_workers();
+        (self.shared.report(done_before), jobs)
+    }
+}
+
+// After shutdown_graceful() or shutdown_now() there's nothing left to do here.
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.stop_timers();
+        self.shared.stop_workers();
+        self.shared.join_workers();
+    }
+}
+
+impl Shared {
+    /* Decide what to do with a new job, waiting or making room in the queue if that's what
+    `overflow` says, usually the pool's own. When the answer is Queue, the job has a place in the
+    queue counted for it.
+    */
+    fn admit(&self, overflow: Overflow) -> Admit {
+        let queued = &self.counters.queued;
+        let Some(capacity) = self.capacity else {
+            queued.fetch_add(1, Ordering::Relaxed);
+            return Admit::Queue;
+        };
+
+        // Take a place in the queue, if there's one free. Several threads may be trying at once.
+        let reserve = || queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < capacity).then_some(n + 1)).is_ok();
+
+        loop {
+            if reserve() {
+                return Admit::Queue;
+            }
+            match overflow {
+                Overflow::Reject => return Admit::Reject,
+                Overflow::CallerRuns => return Admit::RunHere,
+                Overflow::DropOldest => {
+                    // Its place in the queue is handed to the new job. If a worker got to it
+                    // first, there's a free place now anyway.
+                    if let Some(oldest) = self.steal_oldest() {
+                        drop(oldest);
+                        self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                        return Admit::Queue;
+                    }
+                }
+                Overflow::Block => {
+                    let mut guard = lock(&self.full);
+                    self.blocked.fetch_add(1, Ordering::SeqCst);
+                    // Pairs with the fence in made_space(), like sleep() and wake_one().
+                    atomic::fence(Ordering::SeqCst);
+                    let mut admit = Admit::Queue;
+                    while !reserve() {
+                        // Only the timer thread can still be sending jobs by then. See go_off().
+                        if self.shutdown.load(Ordering::SeqCst) {
+                            admit = Admit::Reject;
+                            break;
+                        }
+                        guard = self.space.wait(guard).unwrap_or_else(PoisonError::into_inner);
+                    }
+                    self.blocked.fetch_sub(1, Ordering::SeqCst);
+                    return admit;
+                }
+            }
+        }
+    }
+
+    /* Timers not yet due are dropped. Once the timer thread's gone, nothing else can send jobs,
+    as whoever's shutting the pool down owns it.
+    */
+    fn stop_timers(&self) {
+        let timer_thread = {
+            let mut timers = lock(&self.timers);
+            timers.stopped = true;
+            timers.heap.clear();
+            timers.thread.take()
+        };
+        self.timer_set.notify_one();
+        if let Some(timer_thread) = timer_thread {
+            let _ = timer_thread.join();
+        }
+    }
+
+    // Workers finish every job that's queued, then see the flag and stop.
+    fn stop_workers(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wake_all();
+        // So does anyone waiting for room in the queue, without waiting any more.
+        let _guard = lock(&self.full);
+        self.space.notify_all();
+    }
+
+    // Wait for every worker to finish the jobs that are queued, and stop.
+    fn join_workers(&self) {
+        // Take the workers out one at a time, so the lock isn't held while joining. A worker that
+        // panics needs the lock to put its replacement in the vec, which is then joined as well.
+        loop {
+            let worker = lock(&self.workers).pop();
+            let Some(worker) = worker else {
+                break;
+            };
+            // An Err means the worker panicked. Its replacement is already in the vec.
+            let _ = worker.thread.join();
+        }
+    }
+
+    // Take every job that's still queued, in about the order they'd have run in.
+    fn take_queued(&self) -> Vec<Job> {
+        let stealers: Vec<Stealer<Job>> = read(&self.stealers).iter().flatten().cloned().collect();
+        let mut jobs = Vec::new();
+        for queue in [Priority::High, Priority::Normal, Priority::Low] {
+            loop {
+                let steal = match queue {
+                    // The deques only have Normal priority jobs, taken out of the injector before the rest.
+                    Priority::Normal => stealers.iter().map(Stealer::steal).collect::<Steal<Job>>().or_else(|| self.injector(queue).steal()),
+                    _ => self.injector(queue).steal(),
+                };
+                match steal {
+                    Steal::Success(job) => jobs.push(job),
+                    Steal::Empty => break,
+                    Steal::Retry => {}
+                }
+            }
+        }
+        self.counters.queued.fetch_sub(jobs.len(), Ordering::Relaxed);
+        jobs
+    }
+
+    // No more jobs can be sent, so whichever haven't been done by now never will be, by the pool.
+    fn report(&self, done_before: usize) -> ShutdownReport {
+        let done = self.done.load(Ordering::SeqCst);
+        let dropped = self.counters.dropped.load(Ordering::SeqCst);
+        ShutdownReport {
+            completed: done - done_before,
+            abandoned: self.sent.load(Ordering::SeqCst) - done - dropped,
+        }
+    }
+
+    fn set_timer(self: &Arc<Self>, delay: Duration, task: Task) -> TimerHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut timers = lock(&self.timers);
+
+        if timers.thread.is_none() {
+            let mut builder = thread::Builder::new();
+            if let Some(prefix) = &self.thread_name {
+                builder = builder.name(format!("{prefix}-timer"));
+            }
+            let shared = Arc::clone(self);
+            let thread = builder.spawn(move || shared.run_timers()).expect("failed to spawn the timer thread");
+            timers.thread = Some(thread);
+        }
+
+        let seq = timers.next_seq;
+        timers.next_seq += 1;
+        timers.heap.push(Timer {
+            due: Instant::now() + delay,
+            seq,
+            cancelled: Arc::clone(&cancelled),
+            task,
+        });
+        // The timer thread may be waiting for a later timer than this one.
+        self.timer_set.notify_one();
+
+        TimerHandle { cancelled }
+    }
+
+    // The timer thread: waits for the soonest timer to be due, and sends its job to the workers.
+    fn run_timers(self: &Arc<Self>) {
+        let mut timers = lock(&self.timers);
+        while !timers.stopped {
+            let now = Instant::now();
+            let Some(next) = timers.heap.peek() else {
+                timers = self.timer_set.wait(timers).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+            // A cancelled timer is thrown away when it gets to the top, rather than waited for.
+            if next.due > now && !next.cancelled.load(Ordering::Relaxed) {
+                let wait = next.due - now;
+                timers = self.timer_set.wait_timeout(timers, wait).unwrap_or_else(PoisonError::into_inner).0;
+                continue;
+            }
+
+            let timer = timers.heap.pop().unwrap();
+            if timer.cancelled.load(Ordering::Relaxed) {
+                continue;
+            }
+            // Not locked while sending the job, which may wait for room in the queue.
+            drop(timers);
+            let again = self.go_off(timer, now);
+            timers = lock(&self.timers);
+            if let Some(timer) = again {
+                timers.heap.push(timer);
+            }
+        }
+    }
+
+    // Send a timer's job to the workers. A repeating timer is given back, with its next run due.
+    fn go_off(self: &Arc<Self>, timer: Timer, now: Instant) -> Option<Timer> {
+        let (job, again): (Job, _) = match timer.task {
+            Task::Once(job) => (job, None),
+            Task::Every(interval, f) => {
+                let cancelled = Arc::clone(&timer.cancelled);
+                let run = Arc::clone(&f);
+                // Cancelling stops runs that are queued but haven't started too.
+                let job = Box::new(move || {
+                    if !cancelled.load(Ordering::Relaxed) {
+                        run();
+                    }
+                });
+
+                let mut due = timer.due + interval;
+                if due <= now {
+                    due = now + interval; // A whole run was missed.
+                }
+                let again = Timer {
+                    due,
+                    seq: timer.seq,
+                    cancelled: timer.cancelled,
+                    task: Task::Every(interval, f),
+                };
+                (job, Some(again))
+            }
+        };
+
+        // There's no caller to give a rejected job back to, or to run it instead.
+        match self.admit(self.overflow) {
+            Admit::Queue => self.push(job, Priority::Normal),
+            // It was due, but the pool's shutting down, so it's reported as abandoned.
+            _ if self.shutdown.load(Ordering::SeqCst) => {
+                self.sent.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => {
+                self.counters.skipped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        again
+    }
+
+    fn injector(&self, priority: Priority) -> &Injector<Job> {
+        &self.injectors[priority as usize]
+    }
+
+    /* Queue a job, and add a worker for it if every worker is busy and the pool may grow.
+    A job that's waiting when a worker is about to be free doesn't need a new one.
+    */
+    fn push(self: &Arc<Self>, job: Job, priority: Priority) {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        self.injector(priority).push(job);
+        self.wake_one();
+
+        let counters = &self.counters;
+        let idle = counters.size.load(Ordering::SeqCst).saturating_sub(counters.busy.load(Ordering::Relaxed));
+        if counters.queued.load(Ordering::Relaxed) > idle && self.grow(self.max.load(Ordering::Relaxed)) {
+            self.add_worker();
+        }
+    }
+
+    // Start a new worker, with its own deque. The caller has already counted it in `size`.
+    fn add_worker(self: &Arc<Self>) {
+        // Locked first, in the same order as a worker that's leaving. See retire().
+        let mut workers = lock(&self.workers);
+        // A FIFO deque runs a worker's jobs in the order they were sent.
+        let deque = Deque::new_fifo();
+
+        let mut stealers = write(&self.stealers);
+        let stealer = Some(deque.stealer());
+        let id = match stealers.iter().position(Option::is_none) {
+            Some(id) => {
+                stealers[id] = stealer;
+                id
+            }
+            None => {
+                stealers.push(stealer);
+                stealers.len() - 1
+            }
+        };
+        drop(stealers);
+
+        workers.push(Worker::new(id, deque, Arc::clone(self)));
+    }
+
+    /* The job that's been queued longest, of the lowest priority there is. The injectors are
+    oldest first, and so is each deque. The deques only have Normal priority jobs, which workers
+    took out of the Normal injector in batches, so they're older than the ones still in it.
+    */
+    fn steal_oldest(&self) -> Option<Job> {
+        iter::repeat_with(|| {
+            self.injector(Priority::Low)
+                .steal()
+                .or_else(|| read(&self.stealers).iter().flatten().map(Stealer::steal).collect())
+                .or_else(|| self.injector(Priority::Normal).steal())
+                .or_else(|| self.injector(Priority::High).steal())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+    }
+
+    fn report_panic(&self, worker: usize, payload: &(dyn Any + Send)) {
+        if let Some(hook) = &self.panic_hook {
+            hook(&JobPanic { worker, payload });
+        }
+    }
+
+    // Called by a worker when it takes a job off the queue, in case someone's waiting for room.
+    fn made_space(&self) {
+        if self.capacity.is_none() {
+            return;
+        }
+        atomic::fence(Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.full);
+            self.space.notify_one();
+        }
+    }
+
+    /* The next job for the worker with this deque, from wherever there is one, in the order
+    given in the summary at the top. A steal can fail only because another worker got in the way,
+    in which case it's tried again (Steal::Retry).
+    */
+    fn find_job(&self, id: usize, deque: &Deque<Job>) -> Option<Job> {
+        iter::repeat_with(|| {
+            self.injector(Priority::High)
+                .steal()
+                .or_else(|| deque.pop().map_or(Steal::Empty, Steal::Success))
+                .or_else(|| self.injector(Priority::Normal).steal_batch_and_pop(deque))
+                .or_else(|| {
+                    read(&self.stealers)
+                        .iter()
+                        .enumerate()
+                        .filter(|&(other, _)| other != id)
+                        .filter_map(|(_, stealer)| stealer.as_ref().map(Stealer::steal))
+                        .collect()
+                })
+                .or_else(|| self.injector(Priority::Low).steal())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+    }
+
+    fn has_work(&self) -> bool {
+        self.injectors.iter().any(|injector| !injector.is_empty())
+            || read(&self.stealers).iter().flatten().any(|stealer| !stealer.is_empty())
+    }
+
+    // Count one more worker, unless there are `limit` already. The caller then starts it.
+    fn grow(&self, limit: usize) -> bool {
+        self.counters.size.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < limit).then_some(n + 1)).is_ok()
+    }
+
+    // Count one less worker, unless there are only `limit`. The caller then stops.
+    fn shrink(&self, limit: usize) -> bool {
+        self.counters.size.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n > limit).then_some(n - 1)).is_ok()
+    }
+
+    /* Wait until there might be a job. Jobs still queued at shutdown are run first.
+    A worker is told to retire when there are more than `max` since a resize(), or when it's had
+    nothing to do for the keep-alive and there are more than `min`.
+    */
+    fn sleep(&self) -> Wake {
+        let mut guard = lock(&self.sleep);
+        self.sleeping.fetch_add(1, Ordering::SeqCst);
+        // Pairs with the fence in wake_one(): either this sees the new job, or wake_one() sees
+        // this worker sleeping and wakes it. Without it, both could miss each other.
+        atomic::fence(Ordering::SeqCst);
+
+        let mut timed_out = false;
+        let wake = loop {
+            if self.has_work() {
+                break Wake::Work;
+            }
+            if self.shutdown.load(Ordering::SeqCst) {
+                break Wake::Shutdown;
+            }
+            if self.shrink(self.max.load(Ordering::SeqCst)) || timed_out && self.shrink(self.min.load(Ordering::SeqCst)) {
+                /* A job may have been sent just as this worker decided to go, by a caller that
+                saw it still counted and so didn't add a worker. It mustn't be left with nobody
+                to run it. Same pairing as above, with the fence in wake_one().
+                */
+                atomic::fence(Ordering::SeqCst);
+                if !self.has_work() {
+                    break Wake::Retire;
+                }
+                self.counters.size.fetch_add(1, Ordering::SeqCst);
+                break Wake::Work;
+            }
+
+            let (next, result) = self.wake.wait_timeout(guard, self.keep_alive).unwrap_or_else(PoisonError::into_inner);
+            guard = next;
+            timed_out = result.timed_out();
+        };
+
+        self.sleeping.fetch_sub(1, Ordering::SeqCst);
+        wake
+    }
+
+    /* Take a worker that's leaving out of the pool. Its deque is empty, so its stealer can go,
+    and the id can be given to the next new worker.
+    */
+    fn retire(&self, id: usize) {
+        let mut workers = lock(&self.workers);
+        // Dropping its own JoinHandle leaves the thread to finish on its own, which it's about to.
+        workers.retain(|worker| worker.id != id);
+        write(&self.stealers)[id] = None;
+    }
+
+    // Wake a sleeping worker, if there is one, because there's a job for it.
+    fn wake_one(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            // Taking the lock makes sure the worker is really waiting, not about to.
+            let _guard = lock(&self.sleep);
+            self.wake.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = lock(&self.sleep);
+        self.wake.notify_all();
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Worker {
+    fn new(id: usize, deque: Deque<Job>, shared: Arc<Shared>) -> Self {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &shared.thread_name {
+            builder = builder.name(format!("{prefix}-{id}"));
+        }
+        if let Some(bytes) = shared.stack_size {
+            builder = builder.stack_size(bytes);
+        }
+
+        // Counted out again by its Sentinel, when the thread ends.
+        *lock(&shared.live) += 1;
+        // Like thread::spawn(), which panics if the OS can't start a thread.
+        let thread = builder.spawn(move || {
+            // If this thread dies anyway, it unwinds and the sentinel is dropped, which replaces the worker.
+            let sentinel = Sentinel {
+                id,
+                deque: Some(deque),
+                shared: Arc::clone(&shared),
+            };
+            let deque = sentinel.deque.as_ref().unwrap();
+            let counters = &shared.counters;
+
+            let mut idle_rounds = 0;
+            loop {
+                let Some(job) = shared.find_job(id, deque) else {
+                    /* Going to sleep and being woken up takes a couple of system calls, which is
+                    a lot longer than a tiny job takes to run. So look again a few times first, in
+                    case another job comes along.
+                    */
+                    if idle_rounds < SPIN_ROUNDS {
+                        idle_rounds += 1;
+                        if idle_rounds <= SPIN_ROUNDS / 2 {
+                            hint::spin_loop();
+                        } else {
+                            thread::yield_now();
+                        }
+                        continue;
+                    }
+                    idle_rounds = 0;
+                    match shared.sleep() {
+                        Wake::Work => continue,
+                        Wake::Retire | Wake::Shutdown => break,
+                    }
+                };
+                idle_rounds = 0;
+                // It may have taken a batch. If so, others can steal from it while it's busy.
+                if !deque.is_empty() {
+                    shared.wake_one();
+                }
+
+                counters.queued.fetch_sub(1, Ordering::Relaxed);
+                shared.made_space();
+                counters.busy.fetch_add(1, Ordering::Relaxed);
+                // Each worker will execute job() simultaneously. A panic stops at catch_unwind().
+                let result = panic::catch_unwind(AssertUnwindSafe(job));
+                shared.done.fetch_add(1, Ordering::SeqCst);
+                if let Err(payload) = result {
+                    shared.report_panic(id, payload.as_ref());
+                }
+                counters.busy.fetch_sub(1, Ordering::Relaxed);
+
+                // One too many since a resize(). But not with jobs still in its deque.
+                if deque.is_empty() && shared.shrink(shared.max.load(Ordering::SeqCst)) {
+                    break;
+                }
+            }
+
+            // Leaving early, rather than because the pool is shutting down.
+            if !shared.shutdown.load(Ordering::SeqCst) {
+                shared.retire(id);
+            }
+        })
+        .expect("failed to spawn a worker thread");
+
+        Worker { id, thread }
+    }
+}
+
+/* Lives on a worker's stack. Its drop() runs when the worker thread ends, including when it
+panics and unwinds. Jobs' panics are caught, so that takes a panic somewhere else, like in the
+panic hook. Then it starts a new worker with the same id and the same deque, so the pool keeps
+its full size, and the jobs that were waiting in the dead worker's deque aren't lost.
+*/
+struct Sentinel {
+    id: usize,
+    deque: Option<Deque<Job>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.replace();
+        }
+
+        // Counted out after the replacement's counted in, so the count can't reach 0 in between.
+        let mut live = lock(&self.shared.live);
+        *live -= 1;
+        if *live == 0 {
+            self.shared.all_gone.notify_all();
+        }
+    }
+}
+
+impl Sentinel {
+    fn replace(&mut self) {
+        // The worker died between starting a job and marking it finished.
+        self.shared.counters.busy.fetch_sub(1, Ordering::Relaxed);
+
+        // Hold the lock while starting the replacement, so the pool can't be dropped in between
+        // and miss it.
+        let mut workers = lock(&self.shared.workers);
+        let replacement = Worker::new(self.id, self.deque.take().unwrap(), Arc::clone(&self.shared));
+
+        match workers.iter_mut().find(|worker| worker.id == self.id) {
+            Some(worker) => *worker = replacement, // The old JoinHandle is dropped, its thread is already finishing.
+            // The pool is being dropped and has already taken this worker out to join it.
+            // Put the replacement in so it gets joined too.
+            None => workers.push(replacement),
+        }
+    }
+}
+
+/* A guard dropped while a thread is panicking marks its lock as poisoned. Sentinel::drop()
+always runs while panicking, and nothing else here can panic with a lock held, so the poison
+flag doesn't mean anything for these locks.
+*/
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub type Job = Box<dyn FnOnce() + Send + 'static>; // Job is a trait object for the closure that goes into ThreadPool.execute()
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Barrier, time::Instant};
+
+    #[test]
+    fn panics_go_to_the_hook_and_the_worker_carries_on() {
+        let (panics, reported) = mpsc::channel();
+        let panics = Mutex::new(panics);
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .on_panic(move |panic| {
+                panics.lock().unwrap().send((panic.worker, panic.message().to_string())).unwrap();
+            })
+            .build();
+
+        let (tx, rx) = mpsc::channel();
+        let before = tx.clone();
+        pool.execute(move || before.send(thread::current().id()).unwrap()).unwrap();
+        pool.execute(|| panic!("bad job {}", 1)).unwrap();
+        pool.execute(move || tx.send(thread::current().id()).unwrap()).unwrap();
+
+        assert_eq!(reported.recv_timeout(Duration::from_secs(5)), Ok((0, "bad job 1".to_string())));
+        // The same thread ran the jobs before and after the panic.
+        assert_eq!(rx.recv().unwrap(), rx.recv().unwrap());
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        // A hook that panics kills the worker, which is the only way left for one to die.
+        let pool = ThreadPool::builder().workers(1).on_panic(|_| panic!("bad hook")).build();
+        pool.execute(|| panic!("bad job")).unwrap();
+
+        // The only worker died, so these run on its replacement. Any that were already in the
+        // dead worker's deque are run by the replacement too.
+        let (tx, rx) = mpsc::channel();
+        for i in 0..100 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+
+        for _ in 0..100 {
+            assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+        // Dropping the pool joins the dead worker's replacement, and doesn't panic itself.
+    }
+
+    #[test]
+    fn runs_every_job_before_shutting_down() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(4);
+        for _ in 0..10_000 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        drop(pool);
+        assert_eq!(count.load(Ordering::Relaxed), 10_000);
+    }
+
+    #[test]
+    fn spawned_jobs_return_results_and_panics() {
+        let pool = ThreadPool::new(2);
+
+        let answer = pool.spawn(|| 6 * 7).unwrap();
+        let bad = pool.spawn(|| -> u32 { panic!("bad job") }).unwrap();
+        assert_eq!(answer.join().unwrap(), 42);
+
+        let payload = bad.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"bad job"));
+
+        // The panic was caught, so the worker that ran it is still around for this.
+        let handles: Vec<_> = (0..4).map(|i| pool.spawn(move || i * 2).unwrap()).collect();
+        let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, [0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn stats_count_busy_workers_and_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        let (started, wait_started) = mpsc::channel();
+        let (finish, wait_finish) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait_finish.recv().unwrap();
+        })
+        .unwrap();
+        pool.execute(|| {}).unwrap();
+        wait_started.recv().unwrap();
+
+        assert_eq!(pool.stats(), PoolStats { workers: 1, busy: 1, queued: 1, dropped: 0, skipped: 0 });
+        assert_eq!(pool.stats().idle(), 0);
+
+        finish.send(()).unwrap();
+    }
+
+    // A pool with one worker held up by a job, and room for one more job in its queue, which is taken.
+    fn full_pool(overflow: Overflow) -> (ThreadPool, mpsc::Sender<()>) {
+        let (pool, finish) = held_up(ThreadPool::bounded(1, 1, overflow));
+        pool.execute(|| {}).unwrap();
+        (pool, finish)
+    }
+
+    // The pool, with its one worker held up by a job until something is sent.
+    fn held_up(pool: ThreadPool) -> (ThreadPool, mpsc::Sender<()>) {
+        let (started, wait_started) = mpsc::channel();
+        let (finish, wait_finish) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait_finish.recv().unwrap();
+        })
+        .unwrap();
+        wait_started.recv().unwrap();
+
+        (pool, finish)
+    }
+
+    #[test]
+    fn full_queue_rejects_or_runs_on_caller() {
+        let (pool, finish) = full_pool(Overflow::Reject);
+        // The job comes back, and could still be run some other way.
+        let (tx, rx) = mpsc::channel();
+        let rejected = pool.execute(move || tx.send(1).unwrap()).unwrap_err();
+        (rejected.0)();
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert!(pool.spawn(|| 2).is_err());
+        finish.send(()).unwrap();
+
+        let (pool, finish) = full_pool(Overflow::CallerRuns);
+        let caller = thread::current().id();
+        let handle = pool.spawn(move || thread::current().id() == caller).unwrap();
+        assert!(handle.is_finished());
+        assert!(handle.join().unwrap());
+        finish.send(()).unwrap();
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_or_blocks() {
+        let (pool, finish) = full_pool(Overflow::DropOldest);
+        let first = pool.spawn(|| "first").unwrap();
+        // Pushes out the job that was waiting before it, then gets pushed out by the next one.
+        let second = pool.spawn(|| "second").unwrap();
+        assert!(first.join().is_err());
+        finish.send(()).unwrap();
+        assert_eq!(second.join().unwrap(), "second");
+        // Dropped jobs never ran, but they weren't left behind by the shutdown either.
+        assert_eq!(pool.stats().dropped, 2);
+        assert_eq!(pool.shutdown_graceful(Duration::from_secs(5)).abandoned, 0);
+
+        let (pool, finish) = full_pool(Overflow::Block);
+        let blocked = thread::spawn(move || {
+            pool.execute(|| {}).unwrap();
+            pool.stats()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+        finish.send(()).unwrap();
+        assert!(blocked.join().unwrap().queued <= 1);
+    }
+
+    #[test]
+    fn the_oldest_job_is_dropped_even_from_a_deque() {
+        let (pool, finish) = held_up(ThreadPool::bounded(1, 3, Overflow::DropOldest));
+        let (started, wait_started) = mpsc::channel();
+        let (finish_second, wait_finish_second) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait_finish_second.recv().unwrap();
+        })
+        .unwrap();
+        for name in ["b", "c"] {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(name).unwrap()).unwrap();
+        }
+        // Taking the second job off the injector takes "b" along with it, into the worker's deque.
+        finish.send(()).unwrap();
+        wait_started.recv().unwrap();
+
+        for name in ["d", "e"] {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(name).unwrap()).unwrap();
+        }
+        finish_second.send(()).unwrap();
+        drop(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), ["c", "d", "e"]);
+    }
+
+    #[test]
+    fn higher_priority_jobs_go_first() {
+        let (pool, finish) = held_up(ThreadPool::new(1));
+        let (tx, rx) = mpsc::channel();
+        for (priority, name) in [(Priority::Normal, "normal 1"), (Priority::Low, "low"), (Priority::Normal, "normal 2"), (Priority::High, "high")] {
+            let tx = tx.clone();
+            pool.execute_with_priority(priority, move || tx.send(name).unwrap()).unwrap();
+        }
+        finish.send(()).unwrap();
+
+        let order: Vec<_> = rx.iter().take(4).collect();
+        // The job holding the worker up was running before any of these were sent.
+        assert_eq!(order, ["high", "normal 1", "normal 2", "low"]);
+    }
+
+    #[test]
+    fn builder_names_the_threads() {
+        let pool = ThreadPool::builder().workers(2).thread_name("test-pool").stack_size(256 * 1024).build();
+        let name = pool.spawn(|| thread::current().name().map(String::from)).unwrap().join().unwrap();
+        assert!(name.is_some_and(|name| name == "test-pool-0" || name == "test-pool-1"));
+        assert_eq!(pool.stats().workers, 2);
+    }
+
+    #[test]
+    fn scoped_jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let numbers: Vec<u64> = (1..=1000).collect();
+        let mut sums = [0; 4];
+
+        let chunks = pool.scope(|s| {
+            for (sum, chunk) in sums.iter_mut().zip(numbers.chunks(250)) {
+                s.spawn(move || *sum = chunk.iter().sum());
+            }
+            numbers.chunks(250).len()
+        });
+
+        assert_eq!(chunks, 4);
+        assert_eq!(sums, [31375, 93875, 156375, 218875]);
+    }
+
+    #[test]
+    fn scopes_wait_for_their_jobs_and_pass_on_panics() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicBool::new(false);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("bad job"));
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.store(true, Ordering::Relaxed);
+                });
+            })
+        }));
+
+        assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"bad job"));
+        assert!(finished.load(Ordering::Relaxed));
+
+        // A job that doesn't fit in the queue is run by the caller, rather than lost.
+        let (pool, finish) = full_pool(Overflow::Reject);
+        let mut ran_on = None;
+        pool.scope(|s| s.spawn(|| ran_on = Some(thread::current().id())));
+        assert_eq!(ran_on, Some(thread::current().id()));
+        finish.send(()).unwrap();
+    }
+
+    #[test]
+    fn scoped_jobs_are_never_lost_to_drop_oldest() {
+        // The scope's own jobs don't push each other out when the queue fills up.
+        let pool = ThreadPool::bounded(1, 2, Overflow::DropOldest);
+        let ran = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(5));
+                    ran.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(ran.load(Ordering::Relaxed), 8);
+
+        // But another job can, and then the scope can't pretend it's all done.
+        let (pool, finish) = held_up(ThreadPool::bounded(1, 1, Overflow::DropOldest));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| {
+                    ran.fetch_add(1, Ordering::Relaxed);
+                });
+                pool.execute(|| {}).unwrap();
+            })
+        }));
+        assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"a scoped job was dropped before it ran"));
+        finish.send(()).unwrap();
+        assert_eq!(ran.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn timers_go_off_in_order_unless_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+
+        let later = tx.clone();
+        pool.execute_after(Duration::from_millis(60), move || later.send("later").unwrap());
+        let cancelled = tx.clone();
+        pool.execute_after(Duration::from_millis(30), move || cancelled.send("cancelled").unwrap()).cancel();
+        pool.execute_after(Duration::from_millis(20), move || tx.send("sooner").unwrap());
+
+        assert_eq!(rx.recv().unwrap(), "sooner");
+        assert_eq!(rx.recv().unwrap(), "later");
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        // The cancelled one didn't send anything, and dropped its sender when it was thrown away.
+        assert!(rx.recv().is_err());
+
+        // A timer that isn't due yet doesn't keep the pool from shutting down.
+        pool.execute_after(Duration::from_secs(60), || {});
+        drop(pool);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn periodic_timers_repeat_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        let timer = pool.execute_every(Duration::from_millis(10), move || {
+            let _ = tx.send(());
+        });
+
+        // The third run isn't due until 30ms in. Runs on two workers can be timed apart by less
+        // than the interval, so that's what's checked, rather than the gaps between them.
+        assert_eq!(rx.iter().take(3).count(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        timer.cancel();
+        assert!(timer.is_cancelled());
+        // A run may have been on its way when it was cancelled, but not one after that.
+        thread::sleep(Duration::from_millis(30));
+        while rx.try_recv().is_ok() {}
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn timer_runs_that_find_the_queue_full_are_counted() {
+        for overflow in [Overflow::Reject, Overflow::CallerRuns] {
+            let (pool, finish) = full_pool(overflow);
+            let (tx, rx) = mpsc::channel();
+            pool.execute_after(Duration::ZERO, move || tx.send(()).unwrap());
+
+            let start = Instant::now();
+            while pool.stats().skipped == 0 {
+                assert!(start.elapsed() < Duration::from_secs(5), "{overflow:?}");
+                thread::sleep(Duration::from_millis(1));
+            }
+            finish.send(()).unwrap();
+            drop(pool);
+            // Nor was it run on the timer thread instead.
+            assert!(rx.try_recv().is_err());
+        }
+    }
+
+    #[test]
+    fn graceful_shutdown_finishes_the_queue_unless_out_of_time() {
+        let (pool, finish) = held_up(ThreadPool::new(1));
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            finish.send(()).unwrap();
+        });
+
+        let report = pool.shutdown_graceful(Duration::from_secs(5));
+        assert_eq!(report, ShutdownReport { completed: 6, abandoned: 0 });
+        assert_eq!(count.load(Ordering::Relaxed), 5);
+        releaser.join().unwrap();
+
+        let (pool, finish) = held_up(ThreadPool::new(1));
+        pool.execute(|| {}).unwrap();
+        let start = Instant::now();
+        let report = pool.shutdown_graceful(Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // The one that's still running counts as abandoned too.
+        assert_eq!(report, ShutdownReport { completed: 0, abandoned: 2 });
+        finish.send(()).unwrap();
+
+        // Nor does a timer that's waiting for room in the queue. Its job is abandoned as well.
+        let (pool, finish) = full_pool(Overflow::Block);
+        pool.execute_after(Duration::ZERO, || {});
+        while pool.shared.blocked.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let start = Instant::now();
+        let report = pool.shutdown_graceful(Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(report, ShutdownReport { completed: 0, abandoned: 3 });
+        finish.send(()).unwrap();
+    }
+
+    #[test]
+    fn shutdown_now_gives_back_the_queued_jobs() {
+        let (pool, finish) = held_up(ThreadPool::new(1));
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            finish.send(()).unwrap();
+        });
+
+        // Waits for the running job, but none of the queued ones.
+        let (report, jobs) = pool.shutdown_now();
+        assert_eq!(report, ShutdownReport { completed: 1, abandoned: 3 });
+        assert!(rx.try_recv().is_err());
+
+        for job in jobs {
+            job();
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+        releaser.join().unwrap();
+    }
+
+    // Wait for the pool to get down to this many workers, which it does in its own time.
+    fn wait_for_workers(pool: &ThreadPool, workers: usize) {
+        let start = Instant::now();
+        while pool.stats().workers != workers {
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?}", pool.stats());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn dynamic_pools_grow_when_busy_and_shrink_when_idle() {
+        let pool = ThreadPool::dynamic(1, 3, Duration::from_millis(50));
+        assert_eq!(pool.stats().workers, 1);
+
+        // These can only all get past the barrier if each has a worker of its own.
+        let barrier = Arc::new(Barrier::new(4));
+        let (finish, wait_finish) = mpsc::channel::<()>();
+        let wait_finish = Arc::new(Mutex::new(wait_finish));
+        for _ in 0..3 {
+            let barrier = Arc::clone(&barrier);
+            let wait_finish = Arc::clone(&wait_finish);
+            pool.execute(move || {
+                barrier.wait();
+                let _ = wait_finish.lock().unwrap().recv();
+            })
+            .unwrap();
+        }
+        barrier.wait();
+
+        // No more than `max`, however many jobs are waiting.
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+        assert_eq!(pool.stats().workers, 3);
+
+        drop(finish);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        wait_for_workers(&pool, 1);
+
+        // The one that's left still runs jobs.
+        assert_eq!(pool.spawn(|| 42).unwrap().join().unwrap(), 42);
+    }
+
+    #[test]
+    fn resize_adds_and_removes_workers() {
+        let pool = ThreadPool::dynamic(0, 1, Duration::from_secs(60));
+        assert_eq!(pool.stats().workers, 0);
+        // A pool with no workers starts one for the first job.
+        assert_eq!(pool.spawn(|| 1).unwrap().join().unwrap(), 1);
+        assert_eq!(pool.stats().workers, 1);
+
+        pool.resize(4, 8);
+        assert_eq!(pool.stats().workers, 4);
+
+        // The keep-alive is far off, so only the new `max` makes them go.
+        pool.resize(0, 2);
+        wait_for_workers(&pool, 2);
+
+        let handles: Vec<_> = (0..10).map(|i| pool.spawn(move || i).unwrap()).collect();
+        let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+        assert!(pool.stats().workers <= 2);
+    }
+}
@@ -0,0 +1,355 @@
+/* Summary:
+A reverse proxy: requests under a path prefix are forwarded to one of several upstream servers,
+and the upstream's response is passed back to the client.
+
+    client -> hello -> upstream (one of several, taken in turn)
+
+E.g. to send everything under /api to two other instances of this server:
+$ cargo run -- --port 7879 --access-log access-7879.log
+$ cargo run -- --port 7880 --access-log access-7880.log
+$ cargo run -- --proxy /api=127.0.0.1:7879,127.0.0.1:7880
+$ curl localhost:7878/api/
+
+Upstreams are used round-robin. One that refuses a connection is marked down and skipped until a
+health check finds it up again, so a dead backend costs one failed connect rather than one per
+request. If no upstream can be reached the client gets a 502, and if one is too slow, a 504.
+
+Each forwarded request uses a fresh connection with "Connection: close", which keeps the
+upstream side as simple as our own server: the response ends when the upstream hangs up.
+The upstream's headers are read before the client gets an answer, but the body is streamed
+through as it arrives, so a big download isn't held in memory.
+*/
+
+use std::{
+    io::{self, BufReader, ErrorKind, prelude::*},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    http::{Limits, ParseError, Request, Response},
+    middleware::Handler,
+};
+
+/* Headers that describe one connection rather than the message, so they aren't passed along.
+Content-Length is dropped too, since Response::write_to works it out again. Except without a body
+(the answer to a HEAD, or a 304), when it's the length of the body the client didn't get.
+*/
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Content-Length",
+];
+
+struct Upstream {
+    address: String, // host:port
+    healthy: AtomicBool,
+}
+
+pub struct Proxy {
+    prefix: String,
+    upstreams: Arc<Vec<Upstream>>,
+    next: AtomicUsize, // Which upstream to try first for the next request.
+    strip_prefix: bool,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl Proxy {
+    /* Forward requests whose path starts with `prefix` to the upstreams, given as "host:port".
+    Panics if there are no upstreams, since such a proxy could only ever answer 502.
+    */
+    pub fn new(prefix: &str, upstreams: &[&str]) -> Proxy {
+        assert!(!upstreams.is_empty(), "a proxy needs at least one upstream");
+
+        let upstreams = upstreams
+            .iter()
+            .map(|address| Upstream {
+                address: address.to_string(),
+                healthy: AtomicBool::new(true),
+            })
+            .collect();
+
+        Proxy {
+            prefix: prefix.to_string(),
+            upstreams: Arc::new(upstreams),
+            next: AtomicUsize::new(0),
+            strip_prefix: false,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+
+    // Remove the prefix before forwarding, so /api/users reaches the upstream as /users.
+    pub fn strip_prefix(mut self) -> Self {
+        self.strip_prefix = true;
+        self
+    }
+
+    pub fn timeouts(mut self, connect: Duration, read: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self
+    }
+
+    /* Check every upstream in the background, once per `interval`, by sending it a GET for `path`.
+    Any response below 500 counts as up. The thread stops once the proxy is dropped.
+    */
+    pub fn health_check(self, path: &str, interval: Duration) -> Self {
+        // A Weak reference, so the thread doesn't keep the upstreams alive forever.
+        let upstreams = Arc::downgrade(&self.upstreams);
+        let path = path.to_string();
+        let timeout = self.connect_timeout;
+
+        thread::spawn(move || run_health_checks(upstreams, &path, interval, timeout));
+        self
+    }
+
+    // Whether this proxy handles the request, i.e. its path is under the prefix.
+    pub fn matches(&self, request: &Request) -> bool {
+        match request.path.strip_prefix(self.prefix.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || rest.starts_with('?'),
+            None => false,
+        }
+    }
+
+    // The path to ask the upstream for.
+    fn upstream_path(&self, path: &str) -> String {
+        if !self.strip_prefix {
+            return path.to_string();
+        }
+
+        let rest = path.strip_prefix(self.prefix.trim_end_matches('/')).unwrap_or(path);
+        if rest.starts_with('/') {
+            rest.to_string()
+        } else {
+            format!("/{rest}")
+        }
+    }
+
+    /* Pick upstreams in turn, starting after the one used last time. Healthy ones come first;
+    if they're all marked down, try them anyway in case they've come back since the last check.
+    */
+    fn candidates(&self) -> Vec<&Upstream> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+        let in_turn = (0..count).map(|i| &self.upstreams[(start + i) % count]);
+
+        let (mut healthy, down): (Vec<_>, Vec<_>) = in_turn.partition(|upstream| upstream.healthy.load(Ordering::Relaxed));
+        healthy.extend(down);
+        healthy
+    }
+
+    // Send the request to one upstream and read its response.
+    fn forward(&self, upstream: &Upstream, request: &Request) -> Result<Response, ForwardError> {
+        let mut stream = connect(&upstream.address, self.connect_timeout).map_err(ForwardError::Connect)?;
+        stream.set_read_timeout(Some(self.read_timeout)).map_err(ForwardError::Exchange)?;
+        stream.set_write_timeout(Some(self.read_timeout)).map_err(ForwardError::Exchange)?;
+
+        let head = self.request_head(request);
+        stream
+            .write_all(head.as_bytes())
+            .and_then(|()| stream.write_all(&request.body))
+            .map_err(ForwardError::Exchange)?;
+
+        Response::parse_streaming(BufReader::new(stream), &request.method, &Limits::default()).map_err(|e| match e {
+            ParseError::Io(e) => ForwardError::Exchange(e),
+            e => ForwardError::Exchange(io::Error::new(ErrorKind::InvalidData, e.to_string())),
+        })
+    }
+
+    // The request line and headers as the upstream will see them.
+    fn request_head(&self, request: &Request) -> String {
+        let mut head = format!("{} {} HTTP/1.1\r\n", request.method, self.upstream_path(&request.path));
+
+        for (name, value) in &request.headers {
+            if !is_hop_by_hop(name) && !name.eq_ignore_ascii_case("X-Forwarded-For") {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+
+        // Tell the upstream who the real client is, adding to the list if we're not the first proxy.
+        if let Some(remote_addr) = request.remote_addr {
+            let forwarded_for = match request.header("X-Forwarded-For") {
+                Some(earlier) => format!("{earlier}, {}", remote_addr.ip()),
+                None => remote_addr.ip().to_string(),
+            };
+            head.push_str(&format!("X-Forwarded-For: {forwarded_for}\r\n"));
+        }
+        if !request.body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        head
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &mut Request) -> Response {
+        let mut timed_out = false;
+
+        for upstream in self.candidates() {
+            match self.forward(upstream, request) {
+                Ok(mut response) => {
+                    upstream.healthy.store(true, Ordering::Relaxed);
+                    let length = match response.stream {
+                        None => response.header("Content-Length").map(str::to_string),
+                        Some(_) => None,
+                    };
+                    response.headers.retain(|(name, _)| !is_hop_by_hop(name));
+                    // An empty stream with a length is sent like static_files sends a HEAD.
+                    if let Some(length) = length {
+                        response = response.with_header("Content-Length", length).with_stream(|_| Ok(()));
+                    }
+                    return response;
+                }
+                // Nothing was sent, so it's safe to try the next upstream.
+                Err(ForwardError::Connect(e)) => {
+                    eprintln!("Upstream {} is down: {e}", upstream.address);
+                    upstream.healthy.store(false, Ordering::Relaxed);
+                }
+                /* The upstream may have acted on the request already, so don't send it again.
+                Retrying a POST could e.g. place an order twice.
+                */
+                Err(ForwardError::Exchange(e)) => {
+                    eprintln!("Upstream {} failed: {e}", upstream.address);
+                    timed_out = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+                    break;
+                }
+            }
+        }
+
+        Response::error(if timed_out { 504 } else { 502 })
+    }
+}
+
+enum ForwardError {
+    Connect(io::Error),  // Couldn't reach the upstream.
+    Exchange(io::Error), // Reached it, but sending the request or reading the response failed.
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+    let mut last_error = io::Error::new(ErrorKind::NotFound, format!("{address} didn't resolve"));
+
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|header| header.eq_ignore_ascii_case(name))
+}
+
+fn run_health_checks(upstreams: Weak<Vec<Upstream>>, path: &str, interval: Duration, timeout: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        let Some(upstreams) = upstreams.upgrade() else {
+            return; // The proxy is gone.
+        };
+
+        for upstream in upstreams.iter() {
+            let up = check(&upstream.address, path, timeout).unwrap_or(false);
+            let was_up = upstream.healthy.swap(up, Ordering::Relaxed);
+            if up != was_up {
+                eprintln!("Upstream {} is {}", upstream.address, if up { "up" } else { "down" });
+            }
+        }
+    }
+}
+
+fn check(address: &str, path: &str, timeout: Duration) -> io::Result<bool> {
+    let mut stream = connect(address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")?;
+
+    let response = Response::parse(&mut BufReader::new(stream), "GET")
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(response.status < 500)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    // An upstream that answers one request with `body`, then goes away.
+    fn one_shot_upstream(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = Request::parse(&mut BufReader::new(&mut stream)).unwrap();
+            let mut response = Response::new(200)
+                .with_header("X-Path", &request.path)
+                .with_header("Connection", "close")
+                .with_body(body);
+            response.write_to(&mut stream).unwrap();
+        });
+
+        address
+    }
+
+    fn get(path: &str) -> Request {
+        Request::parse(&mut format!("GET {path} HTTP/1.1\r\nHost: example.com\r\n\r\n").as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn forwards_and_skips_dead_upstreams() {
+        // Grab a free port, then close it, so nothing is listening there.
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let live = one_shot_upstream("from upstream");
+
+        let proxy = Proxy::new("/api", &[&dead, &live]).strip_prefix();
+        let response = proxy.handle(&mut get("/api/users"));
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("X-Path"), Some("/users"));
+        assert_eq!(response.header("Connection"), None);
+        assert!(!proxy.upstreams[0].healthy.load(Ordering::Relaxed));
+
+        // The body is passed through as it's written out.
+        let mut body = Vec::new();
+        response.stream.unwrap().run(&mut body).unwrap();
+        assert_eq!(body, b"from upstream");
+    }
+
+    #[test]
+    fn no_upstream_gets_502() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let proxy = Proxy::new("/api", &[&dead]);
+
+        assert_eq!(proxy.handle(&mut get("/api")).status, 502);
+    }
+
+    #[test]
+    fn matches_whole_path_segments() {
+        let proxy = Proxy::new("/api", &["127.0.0.1:1"]);
+
+        assert!(proxy.matches(&get("/api")));
+        assert!(proxy.matches(&get("/api/users")));
+        assert!(!proxy.matches(&get("/apiary")));
+        assert!(!proxy.matches(&get("/")));
+    }
+}
@@ -0,0 +1,355 @@
+/* Summary:
+Serving connections in thread-pool mode: each connection is handed to a worker, which reads the
+request, runs it through the app, and writes the response. See event_loop.rs for the other mode.
+
+The stream only has to be a Connection, so the same code serves a plain TcpStream and an
+encrypted TLS stream.
+
+Nothing a client sends should be able to panic a worker. A request that can't be parsed gets a
+400, a handler that panics gets a 500, and I/O errors are returned for the caller to report.
+The app never makes these responses, so it's told about them afterwards, for its access log.
+
+Nor should a client be able to hold on to a worker. Every read has a timeout, the whole request
+has a deadline (so sending one byte just before each read times out doesn't work either), and
+requests over the size limits are refused with 408/413/431 before they're read into memory.
+
+A connection that's upgraded to another protocol (a WebSocket) can stay open for hours, so after
+the 101 it's moved to a thread of its own and the worker goes back to the pool.
+
+Serving stops once a Shutdown is triggered. Connections already handed to the pool are still
+finished, because dropping the pool waits for its workers.
+*/
+
+use std::{
+    io::{self, BufReader, ErrorKind, prelude::*},
+    net::{SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ThreadPool,
+    config::Config,
+    http::{Connection, ParseError, Request, Response, Upgrade},
+    middleware::{Handler, Unhandled},
+    pool::Overflow,
+};
+
+// Connections open right now, across every server in the process. See active_connections().
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// How many connections are being served, in either mode, over HTTP or HTTPS.
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+// Counts as one active connection for as long as it's alive.
+pub(crate) struct ActiveConnection;
+
+impl ActiveConnection {
+    pub(crate) fn new() -> ActiveConnection {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/* Tells a server to stop accepting connections. Clones share the same flag, so one can be kept
+to trigger the shutdown while another is passed to serve_threads() or serve_events().
+*/
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    address: SocketAddr, // Where the server listens, so it can be woken up from accept().
+}
+
+impl Shutdown {
+    pub fn new(listener: &TcpListener) -> io::Result<Shutdown> {
+        Ok(Shutdown {
+            requested: Arc::new(AtomicBool::new(false)),
+            address: listener.local_addr()?,
+        })
+    }
+
+    pub fn trigger(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        // A blocked accept() only returns when someone connects, so connect. Nothing is sent.
+        let _ = TcpStream::connect(self.address);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// Accept connections until shut down, serving each one on the pool.
+pub fn serve_threads(
+    listener: TcpListener,
+    pool: &ThreadPool,
+    app: Arc<dyn Handler>,
+    config: Arc<Config>,
+    shutdown: Shutdown,
+) {
+    for stream in listener.incoming() { // A stream is an open connection between client and server.
+        if shutdown.is_triggered() {
+            break;
+        }
+
+        // Accepting can fail, e.g. when we run out of file descriptors. Skip that connection and carry on.
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Accept failed: {e}");
+                continue;
+            }
+        };
+        let accepted = Instant::now();
+        // If the pool is full and turns the connection away, the client is told so on this.
+        let refuse = match (config.queue_size, config.overflow) {
+            (1.., Overflow::Reject) => stream.try_clone().ok(),
+            _ => None,
+        };
+
+        let served = pool.execute({
+            let app = Arc::clone(&app);
+            let config = Arc::clone(&config);
+            move || {
+                if let Err(e) = serve_tcp(stream, &config, app.as_ref()) {
+                    eprintln!("Connection error: {e}");
+                }
+            }
+        });
+        if served.is_err() {
+            eprintln!("Too busy; turned a connection away");
+            if let Some(mut stream) = refuse {
+                let mut response = Response::error(503).with_header("Retry-After", "1");
+                let _ = response.write_to(&mut stream);
+                report_unhandled(app.as_ref(), stream.peer_addr().ok(), None, &response, accepted);
+            }
+        }
+    }
+}
+
+/* Read a request from the stream and write the response. If the response switches protocols, the
+Upgrade is returned, for the caller to run with hand_over() since it's the one that owns the stream.
+*/
+pub fn handle_connection<S: Connection>(
+    mut stream: S,
+    remote_addr: Option<SocketAddr>,
+    app: &dyn Handler,
+    config: &Config,
+) -> io::Result<Option<Upgrade>> {
+    let _active = ActiveConnection::new();
+    let start = Instant::now();
+
+    let deadline = Deadline {
+        connection: &mut stream,
+        deadline: config.request_timeout.map(|timeout| Instant::now() + timeout),
+        read_timeout: config.read_timeout,
+    };
+    let mut buf_reader = BufReader::new(deadline);
+    let parsed = Request::parse_with_limits(&mut buf_reader, &config.limits());
+    drop(buf_reader);
+
+    // The deadline may have shortened the socket's read timeout. Put it back.
+    stream.set_read_timeout(config.read_timeout)?;
+
+    let Some(mut response) = respond(parsed, remote_addr, app, start)? else {
+        return Ok(None);
+    };
+    response.write_to(&mut stream)?;
+
+    Ok(response.upgrade.take())
+}
+
+/* After a 101 the connection belongs to the new protocol until it's done with it. That can be a
+long time, so it's run on a new thread rather than the worker's. It's still counted as active.
+*/
+pub fn hand_over<S: Connection + Send + 'static>(mut stream: S, upgrade: Upgrade) -> io::Result<()> {
+    let active = ActiveConnection::new();
+    thread::Builder::new().name("hello-upgraded".to_string()).spawn(move || {
+        let _active = active;
+        upgrade.run(&mut stream);
+    })?;
+    Ok(())
+}
+
+/* Decide the response to a parsed (or unparseable) request, which started arriving at `start`.
+Ok(None) means there's nobody to answer, and an Err is an I/O error for the caller to report.
+*/
+pub(crate) fn respond(
+    parsed: Result<Request, ParseError>,
+    remote_addr: Option<SocketAddr>,
+    app: &dyn Handler,
+    start: Instant,
+) -> io::Result<Option<Response>> {
+    match parsed {
+        Ok(mut request) => {
+            request.remote_addr = remote_addr;
+            Ok(Some(call_handler(app, &mut request, start)))
+        }
+        Err(e) => {
+            if let Some(status) = e.status() {
+                let response = Response::error(status);
+                report_unhandled(app, remote_addr, None, &response, start);
+                return Ok(Some(response));
+            }
+            match e {
+                ParseError::Io(e) if e.kind() != ErrorKind::UnexpectedEof => Err(e),
+                _ => Ok(None), // The client hung up without sending a whole request.
+            }
+        }
+    }
+}
+
+/* Reads from the connection, but gives up once the deadline has passed. Before each read, the
+socket's read timeout is cut down to the time left, if that's shorter than the usual timeout.
+*/
+struct Deadline<'a, C: Connection> {
+    connection: &'a mut C,
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+}
+
+impl<C: Connection> Read for Deadline<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(ErrorKind::TimedOut, "request took too long"));
+            }
+
+            let timeout = self.read_timeout.map_or(left, |timeout| timeout.min(left));
+            self.connection.set_read_timeout(Some(timeout))?;
+        }
+
+        self.connection.read(buf)
+    }
+}
+
+// Set up a plain TCP connection and serve it.
+pub fn serve_tcp(mut stream: TcpStream, config: &Config, app: &dyn Handler) -> io::Result<()> {
+    set_timeouts(&stream, config)?;
+    let remote_addr = stream.peer_addr().ok();
+
+    if let Some(upgrade) = handle_connection(&mut stream, remote_addr, app, config)? {
+        hand_over(stream, upgrade)?;
+    }
+    Ok(())
+}
+
+// Apply the configured timeouts. These must be set on the socket itself, before any TLS wrapping.
+pub fn set_timeouts(stream: &TcpStream, config: &Config) -> io::Result<()> {
+    stream.set_read_timeout(config.read_timeout)?;
+    stream.set_write_timeout(config.write_timeout)
+}
+
+/* Run the handler, turning a panic into a 500 response.
+AssertUnwindSafe is fine here: if the handler panics, the request is only looked at again
+to report the 500, and is thrown away after that.
+*/
+fn call_handler(app: &dyn Handler, request: &mut Request, start: Instant) -> Response {
+    match panic::catch_unwind(AssertUnwindSafe(|| app.handle(request))) {
+        Ok(response) => response,
+        Err(_) => {
+            let response = Response::error(500);
+            report_unhandled(app, request.remote_addr, Some(request), &response, start);
+            response
+        }
+    }
+}
+
+// Tell the app about a response it didn't make. See Handler::unhandled().
+pub(crate) fn report_unhandled(
+    app: &dyn Handler,
+    remote_addr: Option<SocketAddr>,
+    request: Option<&Request>,
+    response: &Response,
+    start: Instant,
+) {
+    app.unhandled(&Unhandled {
+        remote_addr,
+        request,
+        status: response.status,
+        bytes: response.body.len() as u64,
+        duration: start.elapsed(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A connection that reads from a string and collects what's written back.
+    struct Mock {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Mock {
+        fn set_read_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Serve one request from `input`, and return what was written back.
+    fn serve(input: &str, app: &dyn Handler) -> String {
+        let mut stream = Mock {
+            input: io::Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        handle_connection(&mut stream, None, app, &Config::default()).unwrap();
+
+        String::from_utf8(stream.output).unwrap()
+    }
+
+    #[test]
+    fn malformed_request_gets_400() {
+        let app = |_: &Request| Response::new(200);
+
+        assert!(serve("nonsense\r\n\r\n", &app).starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[test]
+    fn panicking_handler_gets_500() {
+        let app = |_: &Request| -> Response { panic!("handler bug") };
+
+        assert!(serve("GET / HTTP/1.1\r\n\r\n", &app).starts_with("HTTP/1.1 500 Internal Server Error"));
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let app = |_: &Request| Response::new(200);
+        let long_header = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "a".repeat(10_000));
+        let big_body = "POST / HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n";
+
+        assert!(serve(&long_header, &app).starts_with("HTTP/1.1 431"));
+        assert!(serve(big_body, &app).starts_with("HTTP/1.1 413"));
+    }
+}
@@ -0,0 +1,389 @@
+/* Summary:
+Serves the files in a directory, e.g. everything under /static from ./static.
+
+Two things save sending a file again:
+
+Conditional requests. Each file is sent with its last modified time and an ETag, a tag that
+changes whenever the file does. A browser keeps both with its cached copy, and asks again with:
+
+    GET /static/style.css HTTP/1.1
+    If-None-Match: "1a2-65f0c1e2.0"
+    If-Modified-Since: Tue, 12 Mar 2024 09:30:10 GMT
+
+If the file hasn't changed since, the answer is a 304 Not Modified with no body.
+
+Range requests. A client can ask for just part of a file, e.g. to resume a download that was
+cut off after the first 1000 bytes:
+
+    GET /static/big.iso HTTP/1.1
+    Range: bytes=1000-
+    If-Range: "1a2-65f0c1e2.0"
+
+    HTTP/1.1 206 Partial Content
+    Content-Range: bytes 1000-4194303/4194304
+
+If-Range says "only if the file is still this one, otherwise send all of it", so a download
+isn't resumed from a file that has changed underneath it. Asking for several ranges at once
+(bytes=0-99,500-599) gets them all in one multipart/byteranges body, and asking only for
+ranges past the end of the file gets a 416 Range Not Satisfiable.
+
+Files are streamed from disk, never read into memory whole.
+*/
+
+use std::{
+    fs::File,
+    hash::{BuildHasher, RandomState},
+    io::{self, ErrorKind, SeekFrom, prelude::*},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    date::DateTime,
+    http::{Request, Response},
+    middleware::Handler,
+};
+
+// More ranges than this in one request is more likely an attack than a real client.
+const MAX_RANGES: usize = 16;
+
+pub struct StaticFiles {
+    prefix: String,
+    dir: PathBuf,
+}
+
+// The file a request is for, and what's needed to answer it.
+struct FileInfo {
+    file: File,
+    len: u64,
+    modified: SystemTime,
+    etag: String,
+    content_type: &'static str,
+}
+
+// What a Range header asks for.
+#[derive(Debug, PartialEq)]
+enum Ranges {
+    Whole,                  // No Range header, or one that's ignored.
+    Parts(Vec<(u64, u64)>), // First and last byte of each range, within the file.
+    Unsatisfiable,          // Every range is past the end of the file.
+}
+
+impl StaticFiles {
+    // Serve the files in `dir` at the paths under `prefix`: /static/a/b.css is dir/a/b.css.
+    pub fn new(prefix: &str, dir: impl Into<PathBuf>) -> Self {
+        StaticFiles {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+        }
+    }
+
+    // Whether the request is for a path under the prefix. "/static" doesn't cover "/statics".
+    pub fn matches(&self, request: &Request) -> bool {
+        match request.path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || rest.starts_with('?'),
+            None => false,
+        }
+    }
+
+    /* The file on disk for a request path, or None if the path tries to get out of the directory,
+    e.g. /static/../src/main.rs. A directory is served by the index.html in it.
+    */
+    fn file_path(&self, path: &str) -> Option<PathBuf> {
+        let path = path.split('?').next().unwrap();
+        let relative = Path::new(path.strip_prefix(&self.prefix)?.trim_start_matches('/'));
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+
+        let path = self.dir.join(relative);
+        Some(if path.is_dir() { path.join("index.html") } else { path })
+    }
+
+    fn open(&self, path: &str) -> io::Result<FileInfo> {
+        let path = self.file_path(path).ok_or(ErrorKind::NotFound)?;
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        let modified = metadata.modified()?;
+        // Size and modified time together change whenever the contents do, in practice.
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let etag = format!("\"{:x}-{:x}.{:x}\"", metadata.len(), since_epoch.as_secs(), since_epoch.subsec_nanos());
+
+        Ok(FileInfo {
+            file,
+            len: metadata.len(),
+            modified,
+            etag,
+            content_type: content_type(&path),
+        })
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &mut Request) -> Response {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Response::error(405).with_header("Allow", "GET, HEAD");
+        }
+
+        let info = match self.open(&request.path) {
+            Ok(info) => info,
+            Err(e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::PermissionDenied => {
+                return Response::error(404);
+            }
+            Err(e) => {
+                eprintln!("Could not open {}: {e}", request.path);
+                return Response::error(500);
+            }
+        };
+        let last_modified = DateTime::from(info.modified).to_http();
+
+        if not_modified(request, &info.etag, info.modified) {
+            return Response::new(304)
+                .with_header("ETag", info.etag)
+                .with_header("Last-Modified", last_modified);
+        }
+
+        // A Range is only honored if If-Range (when sent) still describes the file.
+        let if_range = request.header("If-Range").is_none_or(|validator| {
+            validator == info.etag || DateTime::parse_http(validator).is_some() && validator == last_modified
+        });
+        let ranges = match request.header("Range") {
+            Some(range) if if_range => parse_ranges(range, info.len),
+            _ => Ranges::Whole,
+        };
+
+        let head_only = request.method == "HEAD";
+        let headers = |status| {
+            Response::new(status)
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("ETag", info.etag.as_str())
+                .with_header("Last-Modified", last_modified.as_str())
+        };
+
+        match ranges {
+            Ranges::Whole => {
+                let response = headers(200)
+                    .with_header("Content-Type", info.content_type)
+                    .with_header("Content-Length", info.len.to_string());
+                send(response, head_only, info.file, vec![(Vec::new(), 0, info.len)], Vec::new())
+            }
+            Ranges::Unsatisfiable => Response::error(416)
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("Content-Range", format!("bytes */{}", info.len)),
+            Ranges::Parts(parts) if parts.len() == 1 => {
+                let (first, last) = parts[0];
+                let response = headers(206)
+                    .with_header("Content-Type", info.content_type)
+                    .with_header("Content-Range", format!("bytes {first}-{last}/{}", info.len))
+                    .with_header("Content-Length", (last - first + 1).to_string());
+                send(response, head_only, info.file, vec![(Vec::new(), first, last - first + 1)], Vec::new())
+            }
+            Ranges::Parts(parts) => {
+                /* Each part gets its own little header, and they're separated by a boundary line
+                that mustn't turn up in the file. A random one almost certainly won't.
+                */
+                let boundary = format!("{:016x}", RandomState::new().hash_one(&info.etag));
+                let sections: Vec<_> = parts
+                    .iter()
+                    .map(|&(first, last)| {
+                        let header = format!(
+                            "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: bytes {first}-{last}/{}\r\n\r\n",
+                            info.content_type, info.len
+                        );
+                        (header.into_bytes(), first, last - first + 1)
+                    })
+                    .collect();
+                let end = format!("\r\n--{boundary}--\r\n").into_bytes();
+
+                let length = sections.iter().map(|(header, _, len)| header.len() as u64 + len).sum::<u64>() + end.len() as u64;
+                let response = headers(206)
+                    .with_header("Content-Type", format!("multipart/byteranges; boundary={boundary}"))
+                    .with_header("Content-Length", length.to_string());
+                send(response, head_only, info.file, sections, end)
+            }
+        }
+    }
+}
+
+/* Attach the body: each section's header followed by that part of the file, and then `end`.
+The length is already in the Content-Length header. The answer to a HEAD has the same headers
+as a GET, but no body at all.
+*/
+fn send(response: Response, head_only: bool, mut file: File, sections: Vec<(Vec<u8>, u64, u64)>, end: Vec<u8>) -> Response {
+    if head_only {
+        return response.with_stream(|_| Ok(()));
+    }
+
+    response.with_stream(move |writer| {
+        for (header, start, len) in sections {
+            writer.write_all(&header)?;
+            file.seek(SeekFrom::Start(start))?;
+            // A file that shrank since it was opened can't fill the promised length.
+            if io::copy(&mut (&mut file).take(len), writer)? < len {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "file got shorter while being sent"));
+            }
+        }
+        writer.write_all(&end)
+    })
+}
+
+/* Whether the client's cached copy is still good. If-None-Match lists the ETags of the copies
+it has, and wins over If-Modified-Since when both are sent, since it's exact.
+*/
+fn not_modified(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        // A "W/" in front marks a weak tag, which is fine here: any equivalent copy will do.
+        let etag = etag.trim_start_matches("W/");
+        return if_none_match.trim() == "*"
+            || if_none_match.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag);
+    }
+
+    // HTTP dates are in whole seconds, so the modified time is cut down to match.
+    let modified = DateTime::from(modified).to_unix();
+    request
+        .header("If-Modified-Since")
+        .and_then(DateTime::parse_http)
+        .is_some_and(|since| modified <= since.to_unix())
+}
+
+/* Read a Range header such as "bytes=0-99,200-,-50": the first hundred bytes, everything from
+200 on, and the last 50. A range may run past the end of the file, and is cut short. A header
+that can't be read, or is in some unit other than bytes, is ignored, as HTTP says to do.
+*/
+fn parse_ranges(header: &str, len: u64) -> Ranges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return Ranges::Whole;
+    };
+
+    let mut parts = Vec::new();
+    for spec in specs.split(',') {
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return Ranges::Whole;
+        };
+        let part = match (first.parse::<u64>(), last.parse::<u64>()) {
+            // -50: the last 50 bytes
+            _ if first.is_empty() => match last.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) => Some((len.saturating_sub(suffix), len.saturating_sub(1))),
+                Err(_) => return Ranges::Whole,
+            },
+            // 200-: from byte 200 to the end
+            (Ok(first), _) if last.is_empty() => Some((first, len.saturating_sub(1))),
+            (Ok(first), Ok(last)) if first <= last => Some((first, last.min(len.saturating_sub(1)))),
+            _ => return Ranges::Whole,
+        };
+        // A range starting past the end of the file (or any range of an empty file) has no bytes.
+        if let Some((first, last)) = part.filter(|&(first, _)| first < len) {
+            parts.push((first, last));
+        }
+    }
+
+    if specs.split(',').count() > MAX_RANGES {
+        Ranges::Whole
+    } else if parts.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Parts(parts)
+    }
+}
+
+// The Content-Type for a file, going by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("GET {path} HTTP/1.1\r\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut body = Vec::new();
+        response.stream.take().unwrap().run(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn parses_range_headers() {
+        assert_eq!(parse_ranges("bytes=0-9", 100), Ranges::Parts(vec![(0, 9)]));
+        assert_eq!(parse_ranges("bytes=90-, -5", 100), Ranges::Parts(vec![(90, 99), (95, 99)]));
+        assert_eq!(parse_ranges("bytes=50-500", 100), Ranges::Parts(vec![(50, 99)]));
+        assert_eq!(parse_ranges("bytes=-500", 100), Ranges::Parts(vec![(0, 99)]));
+        assert_eq!(parse_ranges("bytes=100-, 200-300", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=0-", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=9-0", 100), Ranges::Whole);
+        assert_eq!(parse_ranges("pages=1-2", 100), Ranges::Whole);
+    }
+
+    #[test]
+    fn serves_ranges_and_not_modified() {
+        let dir = TempDir::new("static");
+        fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+        let files = StaticFiles::new("/static", dir.path());
+
+        let whole = files.handle(&mut get("/static/digits.txt", &[]));
+        assert_eq!(whole.status, 200);
+        assert_eq!(whole.header("Content-Length"), Some("10"));
+        let etag = whole.header("ETag").unwrap().to_string();
+        let last_modified = whole.header("Last-Modified").unwrap().to_string();
+        assert_eq!(body(whole), "0123456789");
+
+        let part = files.handle(&mut get("/static/digits.txt", &[("Range", "bytes=2-4")]));
+        assert_eq!(part.status, 206);
+        assert_eq!(part.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(body(part), "234");
+
+        let parts = files.handle(&mut get("/static/digits.txt", &[("Range", "bytes=0-0,-2")]));
+        let length: usize = parts.header("Content-Length").unwrap().parse().unwrap();
+        let text = body(parts);
+        assert_eq!(text.len(), length);
+        assert!(text.contains("Content-Range: bytes 0-0/10\r\n\r\n0\r\n--"));
+        assert!(text.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n--"));
+
+        // A stale If-Range gets the whole file instead.
+        let stale = files.handle(&mut get("/static/digits.txt", &[("Range", "bytes=2-4"), ("If-Range", "\"old\"")]));
+        assert_eq!(stale.status, 200);
+
+        let cached = files.handle(&mut get("/static/digits.txt", &[("If-None-Match", &etag)]));
+        assert_eq!(cached.status, 304);
+        let cached = files.handle(&mut get("/static/digits.txt", &[("If-Modified-Since", &last_modified)]));
+        assert_eq!(cached.status, 304);
+
+        assert_eq!(files.handle(&mut get("/static/digits.txt", &[("Range", "bytes=10-")])).status, 416);
+        assert_eq!(files.handle(&mut get("/static/../digits.txt", &[])).status, 404);
+        assert_eq!(files.handle(&mut get("/static/nope.txt", &[])).status, 404);
+    }
+}
@@ -0,0 +1,642 @@
+/* Summary:
+A small template engine for HTML pages. Templates are files in the doc root:
+
+    {% include "head.html" %}
+    <h1>Hello, {{ user.name }}!</h1>
+    {% if items %}
+      <ul>
+      {% for item in items %}
+        <li>{{ item }}</li>
+      {% endfor %}
+      </ul>
+    {% else %}
+      <p>Nothing here.</p>
+    {% endif %}
+
+{{ value }} inserts a value, HTML-escaped, so text from a request can't inject tags or scripts.
+{{ value | raw }} inserts it as it is, for HTML that's known to be safe.
+{% if value %} is true unless the value is missing, false, 0, "" or an empty list, and
+{% if not value %} is the opposite. {% for x in list %} repeats its body for each item, with
+loop.index counting from 1. {% include "file" %} inserts another template, with the same values.
+A {% tag %} alone on its line is removed together with the line, so they leave no blank lines.
+
+Parsed templates are cached. In dev mode the file's modified time is checked on every render,
+and a template that changed is parsed again, so edits show up without restarting the server.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+// Includes that include themselves would otherwise never end.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// A value that can be put in a template.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0,
+            Value::String(s) => !s.is_empty(),
+            Value::List(items) => !items.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+
+    // Look up a field of a map, e.g. the "name" in user.name
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) => write!(f, "{n}"),
+            Value::String(s) => f.write_str(s),
+            Value::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                Ok(())
+            }
+            Value::Map(_) => f.write_str("[map]"),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Self {
+        Value::Map(context.0)
+    }
+}
+
+// The named values a template is rendered with. Nest one in another for values like user.name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context(BTreeMap<String, Value>);
+
+impl Context {
+    pub fn new() -> Self {
+        Context::default()
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.0.insert(name.to_string(), value.into());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub template: String,
+    pub line: usize, // 0 when the error isn't about a particular line, e.g. a missing file
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.template, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.template, self.line, self.message)
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    Value { path: Vec<String>, raw: bool },
+    If { path: Vec<String>, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    For { name: String, path: Vec<String>, body: Vec<Node> },
+    Include { template: String, line: usize },
+}
+
+// A parsed template.
+#[derive(Debug)]
+pub struct Template {
+    name: String,
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser {
+            name,
+            rest: source,
+            line: 1,
+            line_start: true,
+        };
+        let (nodes, end) = parser.parse_block()?;
+        if let Some((tag, line)) = end {
+            return Err(parser.error_at(line, format!("{{% {tag} %}} without a matching start")));
+        }
+
+        Ok(Template {
+            name: name.to_string(),
+            nodes,
+        })
+    }
+
+    /* Render without access to other templates, so {% include %} is an error.
+    Use Templates::render() for templates that include others.
+    */
+    pub fn render(&self, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope::new(context);
+        self.render_nodes(&self.nodes, &mut scope, None, 0, &mut out)?;
+        Ok(out)
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        scope: &mut Scope<'_>,
+        templates: Option<&Templates>,
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Value { path, raw } => {
+                    let value = scope.lookup(path).map(ToString::to_string).unwrap_or_default();
+                    if *raw {
+                        out.push_str(&value);
+                    } else {
+                        out.push_str(&escape_html(&value));
+                    }
+                }
+                Node::If { path, negate, then, otherwise } => {
+                    let truthy = scope.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.render_nodes(branch, scope, templates, depth, out)?;
+                }
+                Node::For { name, path, body } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::List(items)) => items.clone(),
+                        Some(Value::Null) | None => Vec::new(), // Nothing to loop over
+                        Some(other) => vec![other.clone()],
+                    };
+                    for (i, item) in items.into_iter().enumerate() {
+                        let loop_info = Context::new().with("index", i + 1);
+                        scope.push(vec![(name.clone(), item), ("loop".to_string(), loop_info.into())]);
+                        let result = self.render_nodes(body, scope, templates, depth, out);
+                        scope.pop();
+                        result?;
+                    }
+                }
+                Node::Include { template, line } => {
+                    let Some(templates) = templates else {
+                        return Err(self.error_at(*line, "{% include %} needs Templates::render()".to_string()));
+                    };
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(self.error_at(*line, "includes nested too deep (does it include itself?)".to_string()));
+                    }
+                    let included = templates.get(template)?;
+                    included.render_nodes(&included.nodes, scope, Some(templates), depth + 1, out)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn error_at(&self, line: usize, message: String) -> TemplateError {
+        TemplateError {
+            template: self.name.clone(),
+            line,
+            message,
+        }
+    }
+}
+
+// The values visible while rendering: the context, plus the loop variables of enclosing loops.
+struct Scope<'a> {
+    context: &'a Context,
+    loops: Vec<Vec<(String, Value)>>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(context: &'a Context) -> Self {
+        Scope {
+            context,
+            loops: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, names: Vec<(String, Value)>) {
+        self.loops.push(names);
+    }
+
+    fn pop(&mut self) {
+        self.loops.pop();
+    }
+
+    // Find e.g. ["user", "name"]. Inner loop variables hide outer ones and the context.
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+
+        let mut value = self
+            .loops
+            .iter()
+            .rev()
+            .flat_map(|names| names.iter())
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value)
+            .or_else(|| self.context.0.get(first))?;
+
+        for key in rest {
+            value = value.get(key)?;
+        }
+        Some(value)
+    }
+}
+
+// The nodes of a block, and the tag that ended it with its line. See Parser::parse_block().
+type Block = (Vec<Node>, Option<(String, usize)>);
+
+struct Parser<'a> {
+    name: &'a str,
+    rest: &'a str,
+    line: usize,
+    line_start: bool, // Whether `rest` starts at the beginning of a line
+}
+
+impl Parser<'_> {
+    /* Parse nodes until the end of the source, or until a tag that ends a block ({% else %},
+    {% endif %}, {% endfor %}). That tag is returned with its line, for the caller to check.
+    */
+    fn parse_block(&mut self) -> Result<Block, TemplateError> {
+        let mut nodes = Vec::new();
+
+        loop {
+            // Everything up to the next {{ or {% is plain text.
+            let next = [self.rest.find("{{"), self.rest.find("{%")].into_iter().flatten().min();
+            let Some(start) = next else {
+                if !self.rest.is_empty() {
+                    nodes.push(Node::Text(self.rest.to_string()));
+                }
+                return Ok((nodes, None));
+            };
+            let text = &self.rest[..start];
+            let is_tag = self.rest[start..].starts_with("{%");
+            let close = if is_tag { "%}" } else { "}}" };
+            let Some(end) = self.rest[start..].find(close).map(|end| start + end + 2) else {
+                self.advance(start);
+                return Err(self.error_at(self.line, format!("unclosed {}", &self.rest[..2])));
+            };
+
+            // A tag alone on its line takes the whole line with it, so it doesn't leave a blank line.
+            let line_begin = match text.rfind('\n') {
+                Some(i) => Some(i + 1),
+                None => self.line_start.then_some(0),
+            };
+            let after = &self.rest[end..];
+            let line_end = after.find('\n').map_or(after.len(), |i| i + 1);
+            let own_line = is_tag
+                && line_begin.is_some_and(|i| text[i..].trim().is_empty())
+                && after[..line_end].trim().is_empty();
+
+            let text_end = if own_line { line_begin.unwrap() } else { start };
+            if text_end > 0 {
+                nodes.push(Node::Text(text[..text_end].to_string()));
+            }
+            self.advance(start);
+
+            let line = self.line;
+            let inside = self.rest[2..end - start - 2].trim().to_string();
+            self.advance(end - start + if own_line { line_end } else { 0 });
+            self.line_start = own_line;
+
+            if !is_tag {
+                nodes.push(self.parse_value(&inside, line)?);
+                continue;
+            }
+
+            let words: Vec<&str> = inside.split_whitespace().collect();
+            match words.as_slice() {
+                ["if", "not", path] => nodes.push(self.parse_if(path, true, line)?),
+                ["if", path] => nodes.push(self.parse_if(path, false, line)?),
+                ["for", name, "in", path] => {
+                    let (body, end) = self.parse_block()?;
+                    self.expect_end(end, "endfor", line)?;
+                    nodes.push(Node::For {
+                        name: name.to_string(),
+                        path: parse_path(path),
+                        body,
+                    });
+                }
+                ["include", template] if template.len() >= 2 && template.starts_with('"') && template.ends_with('"') => {
+                    nodes.push(Node::Include {
+                        template: template[1..template.len() - 1].to_string(),
+                        line,
+                    });
+                }
+                ["else"] | ["endif"] | ["endfor"] => return Ok((nodes, Some((words[0].to_string(), line)))),
+                _ => return Err(self.error_at(line, format!("unknown tag {{% {inside} %}}"))),
+            }
+        }
+    }
+
+    fn parse_if(&mut self, path: &str, negate: bool, line: usize) -> Result<Node, TemplateError> {
+        let (then, end) = self.parse_block()?;
+        let otherwise = match end {
+            Some((tag, _)) if tag == "else" => {
+                let (otherwise, end) = self.parse_block()?;
+                self.expect_end(end, "endif", line)?;
+                otherwise
+            }
+            end => {
+                self.expect_end(end, "endif", line)?;
+                Vec::new()
+            }
+        };
+
+        Ok(Node::If {
+            path: parse_path(path),
+            negate,
+            then,
+            otherwise,
+        })
+    }
+
+    // {{ path }} or {{ path | raw }}
+    fn parse_value(&self, inside: &str, line: usize) -> Result<Node, TemplateError> {
+        let (path, raw) = match inside.split_once('|') {
+            Some((path, filter)) if filter.trim() == "raw" => (path.trim(), true),
+            Some((_, filter)) => return Err(self.error_at(line, format!("unknown filter {}", filter.trim()))),
+            None => (inside, false),
+        };
+        if path.is_empty() || path.contains(char::is_whitespace) {
+            return Err(self.error_at(line, format!("expected a name in {{{{ {inside} }}}}")));
+        }
+
+        Ok(Node::Value {
+            path: parse_path(path),
+            raw,
+        })
+    }
+
+    fn expect_end(&self, end: Option<(String, usize)>, expected: &str, start_line: usize) -> Result<(), TemplateError> {
+        match end {
+            Some((tag, _)) if tag == expected => Ok(()),
+            Some((tag, line)) => Err(self.error_at(line, format!("expected {{% {expected} %}}, found {{% {tag} %}}"))),
+            None => Err(self.error_at(start_line, format!("block is never closed with {{% {expected} %}}"))),
+        }
+    }
+
+    // Move past n bytes, counting lines on the way for error messages.
+    fn advance(&mut self, n: usize) {
+        self.line += self.rest[..n].matches('\n').count();
+        self.rest = &self.rest[n..];
+    }
+
+    fn error_at(&self, line: usize, message: String) -> TemplateError {
+        TemplateError {
+            template: self.name.to_string(),
+            line,
+            message,
+        }
+    }
+}
+
+fn parse_path(path: &str) -> Vec<String> {
+    path.split('.').map(String::from).collect()
+}
+
+// Make text safe to put in HTML, both between tags and inside quoted attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Loads templates from a directory and caches them. Shared by all the workers.
+pub struct Templates {
+    dir: PathBuf,
+    reload: bool,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    template: Arc<Template>,
+    modified: Option<SystemTime>,
+}
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Templates {
+            dir: dir.into(),
+            reload: false,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Check for changed files on every render. Handy while editing templates, but costs a stat() each time.
+    pub fn reload_on_change(mut self, reload: bool) -> Self {
+        self.reload = reload;
+        self
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let template = self.get(name)?;
+        let mut out = String::new();
+        let mut scope = Scope::new(context);
+        template.render_nodes(&template.nodes, &mut scope, Some(self), 0, &mut out)?;
+        Ok(out)
+    }
+
+    // The template from the cache, or loaded from its file if it isn't cached (or has changed).
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let error = |message: String| TemplateError {
+            template: name.to_string(),
+            line: 0,
+            message,
+        };
+
+        // Template names come from our own code, but never read outside the directory anyway.
+        let relative = Path::new(name);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(error("template names must be relative paths inside the doc root".to_string()));
+        }
+        let path = self.dir.join(relative);
+
+        let modified = if self.reload {
+            fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()
+        } else {
+            None
+        };
+
+        if let Some(cached) = self.cache.lock().unwrap().get(name)
+            && (!self.reload || cached.modified == modified)
+        {
+            return Ok(Arc::clone(&cached.template));
+        }
+
+        // Read and parse without holding the lock, so other templates can still be rendered.
+        let source = fs::read_to_string(&path).map_err(|e| error(format!("can't read {}: {e}", path.display())))?;
+        let template = Arc::new(Template::parse(name, &source)?);
+
+        self.cache.lock().unwrap().insert(
+            name.to_string(),
+            Cached {
+                template: Arc::clone(&template),
+                modified,
+            },
+        );
+        Ok(template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn render(source: &str, context: &Context) -> String {
+        Template::parse("test", source).unwrap().render(context).unwrap()
+    }
+
+    #[test]
+    fn interpolates_and_escapes() {
+        let context = Context::new()
+            .with("name", "<b>Ferris</b>")
+            .with("user", Context::new().with("age", 9i64));
+
+        assert_eq!(render("Hi {{ name }}, {{user.age}}", &context), "Hi &lt;b&gt;Ferris&lt;/b&gt;, 9");
+        assert_eq!(render("{{ name | raw }}{{ missing }}", &context), "<b>Ferris</b>");
+    }
+
+    #[test]
+    fn loops_and_conditionals() {
+        let context = Context::new().with("items", vec!["a", "b"]).with("empty", Vec::<String>::new());
+        let source = "{% for item in items %}{{ loop.index }}={{ item }} {% endfor %}\
+                      {% if empty %}yes{% else %}no{% endif %}{% if not empty %}!{% endif %}";
+
+        assert_eq!(render(source, &context), "1=a 2=b no!");
+    }
+
+    #[test]
+    fn tags_on_their_own_lines_leave_no_blank_lines() {
+        let context = Context::new().with("items", vec!["a", "b"]);
+        let source = "<ul>\n  {% for item in items %}\n  <li>{{ item }}</li>\n  {% endfor %}\n</ul>\n";
+
+        assert_eq!(render(source, &context), "<ul>\n  <li>a</li>\n  <li>b</li>\n</ul>\n");
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let error = Template::parse("page.html", "ok\n{% if x %}\n{% endfor %}").unwrap_err();
+        assert_eq!(error.to_string(), "page.html:3: expected {% endif %}, found {% endfor %}");
+
+        assert!(Template::parse("page.html", "{{ unclosed").is_err());
+        assert!(Template::parse("page.html", "{% for x %}").is_err());
+    }
+
+    #[test]
+    fn includes_and_reloads_from_dir() {
+        let dir = TempDir::new("templates");
+        fs::write(dir.join("page.html"), "[{% include \"part.html\" %}]").unwrap();
+        fs::write(dir.join("part.html"), "{{ x }}").unwrap();
+
+        let templates = Templates::new(dir.path()).reload_on_change(true);
+        let context = Context::new().with("x", "one");
+        assert_eq!(templates.render("page.html", &context).unwrap(), "[one]");
+
+        // Make sure the modified time differs, even on filesystems with coarse timestamps.
+        fs::write(dir.join("part.html"), "{{ x }}{{ x }}").unwrap();
+        let file = fs::File::options().write(true).open(dir.join("part.html")).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(templates.render("page.html", &context).unwrap(), "[oneone]");
+
+        assert!(templates.render("../secret", &context).is_err());
+    }
+}
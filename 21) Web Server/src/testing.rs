@@ -0,0 +1,291 @@
+/* Summary:
+Helpers for testing the server over real sockets, without a browser.
+
+A TestServer runs an app on an ephemeral port (the OS picks a free one, so tests can run in
+parallel), and a small client sends it requests:
+
+    let server = TestServer::start(app);
+    server.get("/").assert_status(200).assert_body_contains("Hello");
+    server.request("POST", "/echo").header("X-Id", "1").body("hi").send().assert_status(200);
+    server.shutdown();
+
+The assertions panic with the whole response in the message, so a failing test shows what the
+server actually sent. They return the response again, so several can be chained.
+
+Tests that need files on disk (a doc root, a config file, a log) get a directory of their own
+with TempDir, which is removed again afterwards, even when the test fails.
+*/
+
+use std::{
+    env, fmt, fs,
+    io::{self, BufReader, prelude::*},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    ThreadPool,
+    config::{Config, Mode},
+    event_loop::serve_events,
+    http::Response,
+    middleware::Handler,
+    server::{Shutdown, serve_threads},
+};
+
+pub struct TestServer {
+    address: SocketAddr,
+    shutdown: Shutdown,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    // Serve the app with the default config.
+    pub fn start(app: impl Handler + 'static) -> TestServer {
+        TestServer::start_with(Arc::new(app), Config::default())
+    }
+
+    /* Serve the app with the given config, in the config's mode. The address and port are
+    ignored; the server always listens on 127.0.0.1 and a port of the OS's choosing.
+    */
+    pub fn start_with(app: Arc<dyn Handler>, config: Config) -> TestServer {
+        let pool = config.thread_pool();
+        TestServer::start_with_pool(app, config, pool)
+    }
+
+    // The same, on a pool made beforehand, e.g. because the app reports on it.
+    pub fn start_with_pool(app: Arc<dyn Handler>, config: Config, pool: ThreadPool) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("no free port for the test server");
+        let address = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new(&listener).unwrap();
+
+        let config = Arc::new(config);
+        let server_shutdown = shutdown.clone();
+
+        // The pool lives on the server's thread, so joining the thread also waits for the workers.
+        let thread = thread::spawn(move || {
+            match config.mode {
+                Mode::Threads => serve_threads(listener, &pool, app, config, server_shutdown),
+                Mode::Events => serve_events(listener, &pool, app, config, server_shutdown).unwrap(),
+            }
+        });
+
+        TestServer {
+            address,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path).send()
+    }
+
+    pub fn request(&self, method: &str, path: &str) -> TestRequest {
+        TestRequest::new(self.address, method, path)
+    }
+
+    /* Stop accepting connections and wait until the server has stopped, including any
+    requests it was in the middle of.
+    */
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown.trigger();
+            thread.join().expect("the test server panicked");
+        }
+    }
+}
+
+// Servers are stopped when they go out of scope too, e.g. when a test fails part way through.
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.shutdown.trigger(); // Don't wait while unwinding; a second panic would abort.
+        } else {
+            self.stop();
+        }
+    }
+}
+
+// A request to send. It works against any server, not only a TestServer.
+pub struct TestRequest {
+    address: SocketAddr,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    timeout: Duration,
+}
+
+impl TestRequest {
+    pub fn new(address: SocketAddr, method: &str, path: &str) -> TestRequest {
+        TestRequest {
+            address,
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: vec![("Host".to_string(), address.to_string())],
+            body: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    // Set a header, replacing any earlier one with the same name (e.g. the default Host).
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // How long to wait for the response. A test shouldn't hang forever on a server that doesn't answer.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Send the request and read the response. Panics if that fails, since the test can't go on.
+    pub fn send(self) -> TestResponse {
+        let description = format!("{} {}", self.method, self.path);
+        self.try_send().unwrap_or_else(|e| panic!("{description} failed: {e}"))
+    }
+
+    // Like send(), for tests that expect the request to fail.
+    pub fn try_send(self) -> io::Result<TestResponse> {
+        let mut stream = TcpStream::connect_timeout(&self.address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+
+        let response = Response::parse(&mut BufReader::new(stream), &self.method)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        Ok(TestResponse { response })
+    }
+}
+
+// A response, with assertions about it.
+pub struct TestResponse {
+    pub response: Response,
+}
+
+impl TestResponse {
+    pub fn status(&self) -> u16 {
+        self.response.status
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.response.header(name)
+    }
+
+    // The body as text. Anything that isn't UTF-8 is replaced, which is fine for a test.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.response.body).into_owned()
+    }
+
+    #[track_caller]
+    pub fn assert_status(&self, status: u16) -> &Self {
+        assert_eq!(self.status(), status, "unexpected status in:\n{self}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(self.header(name), Some(value), "unexpected {name} header in:\n{self}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_has_header(&self, name: &str) -> &Self {
+        assert!(self.header(name).is_some(), "no {name} header in:\n{self}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body(&self, body: &str) -> &Self {
+        assert_eq!(self.text(), body, "unexpected body in:\n{self}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body_contains(&self, text: &str) -> &Self {
+        assert!(self.text().contains(text), "body doesn't contain {text:?} in:\n{self}");
+        self
+    }
+}
+
+// Show the response roughly as it came over the wire.
+impl fmt::Display for TestResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "HTTP/1.1 {}", self.status())?;
+        for (name, value) in &self.response.headers {
+            writeln!(f, "{name}: {value}")?;
+        }
+        write!(f, "\n{}", self.text())
+    }
+}
+
+/* A directory for one test's files, in the system's temp directory, removed when it's dropped.
+Its name has the process id and a count in it, so no two tests share one, whether they run in
+the same test binary or another.
+*/
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    // Make a new, empty directory, with `name` in its name to tell which test it's for.
+    pub fn new(name: &str) -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("hello-{name}-{}-{count}", process::id()));
+
+        // Left over from an earlier run that had the same process id, and was killed.
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("couldn't create a temporary directory");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // A path inside the directory.
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,97 @@
+/* Summary:
+HTTPS support, using rustls. Only compiled with the "tls" feature:
+$ cargo run --features tls -- --https-port 7443 --tls-cert cert.pem --tls-key key.pem
+
+For local testing, a self-signed certificate can be made with
+$ openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=localhost" \
+      -keyout key.pem -out cert.pem
+and then
+$ curl --cacert cert.pem https://localhost:7443/
+*/
+
+use std::{
+    io::{self, prelude::*},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{
+    ServerConfig, ServerConnection, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+
+use crate::{config::Config, http::Connection, middleware::Handler, server};
+
+// A TcpStream with TLS on top. Reading and writing it encrypts and decrypts transparently.
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+impl Connection for TlsStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /* Load the certificate chain and private key from PEM files.
+    The cert file may hold the whole chain, starting with the server's own certificate.
+    */
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid_data(format!("{}: {e}", cert_path.display())))?;
+        if certs.is_empty() {
+            return Err(invalid_data(format!("{}: no certificates found", cert_path.display())));
+        }
+
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| invalid_data(format!("{}: {e}", key_path.display())))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| invalid_data(e.to_string()))?;
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    /* Wrap an accepted connection. No I/O happens yet; the handshake is done on the first
+    read, so it runs on the worker thread rather than holding up the accept loop.
+    */
+    pub fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    // The TLS version of server::serve_tcp().
+    pub fn serve(&self, stream: TcpStream, config: &Config, app: &dyn Handler) -> io::Result<()> {
+        server::set_timeouts(&stream, config)?;
+        let remote_addr = stream.peer_addr().ok();
+
+        let mut stream = self.accept(stream)?;
+        match server::handle_connection(&mut stream, remote_addr, app, config)? {
+            Some(upgrade) => server::hand_over(stream, upgrade),
+            None => close(&mut stream),
+        }
+    }
+}
+
+// Tell the client we're done, so it knows the response wasn't cut off by an attacker.
+pub fn close(stream: &mut TlsStream) -> io::Result<()> {
+    stream.conn.send_close_notify();
+    stream.flush()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
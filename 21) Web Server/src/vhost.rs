@@ -0,0 +1,115 @@
+/* Summary:
+Virtual hosts: one server, several sites. Each request goes to the site named in its Host header,
+each with its own handler (and so its own doc root and routes):
+
+    GET / HTTP/1.1
+    Host: docs.example.com
+
+e.g.
+$ cargo run -- --vhost docs.localhost=./docs --vhost wiki.localhost=./wiki
+$ curl -H "Host: docs.localhost" localhost:7878/
+
+A name like "*.example.com" matches any subdomain of example.com (but not example.com itself).
+Requests for a host nobody claimed, or with no Host header at all, go to the default site.
+*/
+
+use crate::{
+    http::{Request, Response},
+    middleware::Handler,
+};
+
+pub struct VirtualHosts {
+    hosts: Vec<(String, Box<dyn Handler>)>,
+    default: Box<dyn Handler>,
+}
+
+impl VirtualHosts {
+    // Requests that don't match any host are handled by `default`.
+    pub fn new(default: impl Handler + 'static) -> Self {
+        VirtualHosts {
+            hosts: Vec::new(),
+            default: Box::new(default),
+        }
+    }
+
+    // Serve requests for this host name with this handler. Exact names win over wildcards.
+    pub fn host(mut self, name: &str, handler: impl Handler + 'static) -> Self {
+        self.hosts.push((normalize(name), Box::new(handler)));
+        self
+    }
+
+    // The handler for a Host header.
+    fn handler_for(&self, host: Option<&str>) -> &dyn Handler {
+        let Some(host) = host.map(normalize) else {
+            return self.default.as_ref();
+        };
+
+        let exact = self.hosts.iter().find(|(name, _)| *name == host);
+        // Of the wildcards, the longest (most specific) one that matches.
+        let wildcard = || {
+            self.hosts
+                .iter()
+                .filter(|(name, _)| name.strip_prefix('*').is_some_and(|suffix| host.ends_with(suffix)))
+                .max_by_key(|(name, _)| name.len())
+        };
+
+        match exact.or_else(wildcard) {
+            Some((_, handler)) => handler.as_ref(),
+            None => self.default.as_ref(),
+        }
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handle(&self, request: &mut Request) -> Response {
+        let host = request.header("Host").map(str::to_string);
+        self.handler_for(host.as_deref()).handle(request)
+    }
+}
+
+/* Host names are case insensitive, and the Host header may have a port and a trailing dot:
+"Example.COM.:7878" is the same site as "example.com".
+*/
+fn normalize(host: &str) -> String {
+    let host = host.trim();
+    // An IPv6 address is in brackets, and has colons of its own: [::1]:7878
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &'static str) -> impl Handler {
+        move |_: &Request| Response::new(200).with_body(name)
+    }
+
+    fn body_for(hosts: &VirtualHosts, host: Option<&str>) -> String {
+        let raw = match host {
+            Some(host) => format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n"),
+            None => "GET / HTTP/1.0\r\n\r\n".to_string(),
+        };
+        let response = hosts.handle(&mut Request::parse(&mut raw.as_bytes()).unwrap());
+        String::from_utf8(response.body).unwrap()
+    }
+
+    #[test]
+    fn routes_by_host() {
+        let hosts = VirtualHosts::new(site("default"))
+            .host("docs.example.com", site("docs"))
+            .host("*.example.com", site("any"))
+            .host("*.api.example.com", site("api"));
+
+        assert_eq!(body_for(&hosts, Some("docs.example.com")), "docs");
+        assert_eq!(body_for(&hosts, Some("DOCS.Example.com.:7878")), "docs");
+        assert_eq!(body_for(&hosts, Some("wiki.example.com")), "any");
+        assert_eq!(body_for(&hosts, Some("v1.api.example.com")), "api");
+        assert_eq!(body_for(&hosts, Some("example.com")), "default");
+        assert_eq!(body_for(&hosts, Some("[::1]:7878")), "default");
+        assert_eq!(body_for(&hosts, None), "default");
+    }
+}
@@ -0,0 +1,593 @@
+/* Summary:
+WebSockets (RFC 6455). Lets the server push messages to a page without it polling.
+
+A handler accepts the upgrade by returning websocket::upgrade(request, on_open). The server
+sends the 101 response, and on_open is then called on its own thread with a WebSocket,
+which is a pair of channels: send() pushes a message to the client, recv() waits for one.
+
+    router -> upgrade(request, |ws| {
+        while let Some(message) = ws.recv() {
+            ws.send(message).ok(); // echo
+        }
+    })
+
+After the 101 the connection gets a thread of its own, so the worker that accepted it can go
+back to the pool. That thread moves frames between the socket and the channels, answers pings
+and handles the closing handshake. A client that has sent nothing for IDLE is pinged, and if it
+still hasn't answered after another IDLE, it's gone and the connection is closed.
+
+A client that breaks the protocol, e.g. with a frame that's too big or uses an extension that
+wasn't agreed on, is sent a close with status 1002 and the connection is dropped.
+
+Each open WebSocket costs two threads, so at most MAX_SOCKETS can be open at once. Past that
+the upgrade is refused with a 503. Only the thread-pool server mode supports upgrades.
+*/
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::http::{Connection, Request, Response, Upgrade};
+
+// Every server hashes the client's key with this fixed GUID, to prove it speaks WebSocket.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// How long to wait for data before checking for outgoing messages.
+const POLL: Duration = Duration::from_millis(50);
+
+// The largest message we'll buffer, so a client can't make us allocate without limit.
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+// How long a client can be silent before it's pinged, and then before it's given up on.
+const IDLE: Duration = Duration::from_secs(30);
+
+// How many WebSockets can be open at once, and how many are.
+const MAX_SOCKETS: usize = 256;
+static OPEN_SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>), // Status code and reason
+}
+
+// One end of an open WebSocket.
+pub struct WebSocket {
+    outgoing: Sender<Message>,
+    incoming: Receiver<Message>,
+}
+
+impl WebSocket {
+    // Queue a message for the client. Fails once the connection is closed.
+    pub fn send(&self, message: Message) -> Result<(), mpsc::SendError<Message>> {
+        self.outgoing.send(message)
+    }
+
+    // Wait for the next message. None once the connection is closed.
+    pub fn recv(&self) -> Option<Message> {
+        self.incoming.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Message, RecvTimeoutError> {
+        self.incoming.recv_timeout(timeout)
+    }
+
+    // Another handle for sending, e.g. to push messages from a different thread.
+    pub fn sender(&self) -> Sender<Message> {
+        self.outgoing.clone()
+    }
+}
+
+/* Accept a WebSocket handshake. If the request isn't a valid one, an error response is returned
+instead, and on_open is never called.
+*/
+pub fn upgrade(request: &Request, on_open: impl FnOnce(WebSocket) + Send + 'static) -> Response {
+    let wants_upgrade = request
+        .header("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let connection_upgrade = request.header("Connection").is_some_and(|connection| {
+        connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+
+    if request.method != "GET" || !wants_upgrade || !connection_upgrade {
+        return Response::error(400);
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Response::error(426).with_header("Sec-WebSocket-Version", "13");
+    }
+    let Some(key) = request.header("Sec-WebSocket-Key") else {
+        return Response::error(400);
+    };
+    let Some(slot) = Slot::take(&OPEN_SOCKETS, MAX_SOCKETS) else {
+        return Response::error(503).with_header("Retry-After", "1");
+    };
+
+    let mut response = Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key));
+    // The slot is given back when the connection closes, or if the upgrade never happens.
+    response.upgrade = Some(Upgrade::new(move |connection| {
+        let _slot = slot;
+        run(connection, on_open);
+    }));
+
+    response
+}
+
+// One of the MAX_SOCKETS places for an open WebSocket, held until it's dropped.
+struct Slot(&'static AtomicUsize);
+
+impl Slot {
+    fn take(open: &'static AtomicUsize, max: usize) -> Option<Slot> {
+        open.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+            .ok()
+            .map(|_| Slot(open))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/* Pump frames between the connection and the handler's channels until either side closes.
+Reads use a short timeout, so one thread can both wait for the client and send pushes.
+*/
+fn run(connection: &mut dyn Connection, on_open: impl FnOnce(WebSocket) + Send + 'static) {
+    let (to_handler, incoming) = mpsc::channel();
+    let (outgoing, from_handler) = mpsc::channel();
+
+    // The handler is detached. It finds out the connection is gone when recv() returns None
+    // or send() fails.
+    thread::spawn(move || on_open(WebSocket { outgoing, incoming }));
+
+    if let Err(e) = pump(connection, &to_handler, &from_handler, IDLE)
+        && !matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset)
+    {
+        eprintln!("WebSocket error: {e}");
+    }
+}
+
+// Fail the connection on a protocol error, as RFC 6455 section 7.1.7 asks.
+fn pump(
+    connection: &mut dyn Connection,
+    to_handler: &Sender<Message>,
+    from_handler: &Receiver<Message>,
+    idle: Duration,
+) -> io::Result<()> {
+    let result = exchange(connection, to_handler, from_handler, idle);
+    if let Err(e) = &result
+        && e.get_ref().is_some_and(|e| e.is::<ProtocolError>())
+    {
+        let _ = write_message(connection, &Message::Close(Some((1002, String::new()))));
+    }
+    result
+}
+
+fn exchange(
+    connection: &mut dyn Connection,
+    to_handler: &Sender<Message>,
+    from_handler: &Receiver<Message>,
+    idle: Duration,
+) -> io::Result<()> {
+    connection.set_read_timeout(Some(POLL))?;
+
+    let mut buffer = Vec::new();
+    let mut fragments: Option<(u8, Vec<u8>)> = None; // A message that arrived in pieces so far
+    let mut chunk = [0; 4096];
+    let mut last_heard = Instant::now();
+    let mut pinged = false;
+
+    loop {
+        // Send everything the handler has queued up.
+        loop {
+            match from_handler.try_recv() {
+                Ok(message) => {
+                    let closing = matches!(message, Message::Close(_));
+                    write_message(connection, &message)?;
+                    if closing {
+                        return Ok(());
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // The handler is done. Say goodbye.
+                Err(TryRecvError::Disconnected) => {
+                    return write_message(connection, &Message::Close(Some((1000, String::new()))));
+                }
+            }
+        }
+
+        // Anything at all from the client, a pong included, shows it's still there.
+        let quiet = last_heard.elapsed();
+        if quiet >= idle * 2 {
+            return write_message(connection, &Message::Close(Some((1001, "idle".to_string()))));
+        }
+        if quiet >= idle && !pinged {
+            write_message(connection, &Message::Ping(Vec::new()))?;
+            pinged = true;
+        }
+
+        match connection.read(&mut chunk) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk[..n]);
+                last_heard = Instant::now();
+                pinged = false;
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => continue,
+            Err(e) => return Err(e),
+        }
+
+        // Handle every complete frame in the buffer.
+        while let Some((frame, used)) = parse_frame(&buffer)? {
+            buffer.drain(..used);
+
+            let message = match (frame.opcode, &mut fragments) {
+                // Continuation of a fragmented message.
+                (0x0, Some((_, data))) => {
+                    data.extend_from_slice(&frame.payload);
+                    if data.len() > MAX_MESSAGE {
+                        write_message(connection, &Message::Close(Some((1009, String::new()))))?;
+                        return Ok(());
+                    }
+                    if !frame.fin {
+                        continue;
+                    }
+                    let (opcode, data) = fragments.take().unwrap();
+                    to_message(opcode, data)?
+                }
+                (0x0, None) => return Err(protocol_error("continuation without a start")),
+                // First piece of a fragmented message.
+                (0x1 | 0x2, None) if !frame.fin => {
+                    fragments = Some((frame.opcode, frame.payload));
+                    continue;
+                }
+                (0x1 | 0x2, Some(_)) => return Err(protocol_error("new message inside a fragmented one")),
+                (opcode, _) => to_message(opcode, frame.payload)?,
+            };
+
+            match message {
+                Message::Ping(data) => write_message(connection, &Message::Pong(data))?,
+                Message::Close(reason) => {
+                    // Echo the close back, as the closing handshake requires.
+                    let code = reason.map(|(code, _)| code).unwrap_or(1000);
+                    write_message(connection, &Message::Close(Some((code, String::new()))))?;
+                    return Ok(());
+                }
+                message => {
+                    // If the handler has gone, there's no one to deliver to. Keep going until
+                    // its outgoing side disconnects, which closes the socket above.
+                    let _ = to_handler.send(message);
+                }
+            }
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/* Parse one frame from the front of the buffer. Ok(None) means it hasn't all arrived yet.
+Returns the frame and how many bytes of the buffer it used.
+No extensions are agreed in the handshake, so the RSV bits must be 0. Control frames (close, ping
+and pong, opcodes 0x8 and up) can't be fragmented, and carry at most 125 bytes.
+
+     0               1               2               3
+    +-+-+-+-+-------+-+-------------+-------------------------------+
+    |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+    |I|S|S|S|  (4)  |A|     (7)     |            (16/64)            |
+    |N|V|V|V|       |S|             |                               |
+    +-+-+-+-+-------+-+-------------+-------------------------------+
+    |  Masking key (4, always sent by clients)  |   Payload data... |
+*/
+fn parse_frame(buffer: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0F;
+    let masked = buffer[1] & 0x80 != 0;
+    if buffer[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set"));
+    }
+    if !masked {
+        return Err(protocol_error("client frames must be masked"));
+    }
+    if opcode >= 0x8 && !fin {
+        return Err(protocol_error("fragmented control frame"));
+    }
+    if opcode >= 0x8 && buffer[1] & 0x7F > 125 {
+        return Err(protocol_error("control frame too long"));
+    }
+
+    let (length, mut offset) = match buffer[1] & 0x7F {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if length > MAX_MESSAGE as u64 {
+        return Err(protocol_error("frame too large"));
+    }
+    let length = length as usize;
+
+    if buffer.len() < offset + 4 + length {
+        return Ok(None);
+    }
+    let mask = &buffer[offset..offset + 4];
+    offset += 4;
+
+    let payload = buffer[offset..offset + length]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, offset + length)))
+}
+
+fn to_message(opcode: u8, payload: Vec<u8>) -> io::Result<Message> {
+    Ok(match opcode {
+        0x1 => Message::Text(String::from_utf8(payload).map_err(|_| protocol_error("text isn't UTF-8"))?),
+        0x2 => Message::Binary(payload),
+        0x8 if payload.len() >= 2 => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            Message::Close(Some((code, String::from_utf8_lossy(&payload[2..]).into_owned())))
+        }
+        0x8 => Message::Close(None),
+        0x9 => Message::Ping(payload),
+        0xA => Message::Pong(payload),
+        _ => return Err(protocol_error("unknown opcode")),
+    })
+}
+
+// Servers send whole, unmasked frames.
+fn write_message(connection: &mut dyn Connection, message: &Message) -> io::Result<()> {
+    let (opcode, payload) = match message {
+        Message::Text(text) => (0x1, text.as_bytes().to_vec()),
+        Message::Binary(data) => (0x2, data.clone()),
+        Message::Close(None) => (0x8, Vec::new()),
+        Message::Close(Some((code, reason))) => {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            (0x8, payload)
+        }
+        Message::Ping(data) => (0x9, data.clone()),
+        Message::Pong(data) => (0xA, data.clone()),
+    };
+
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&payload);
+
+    connection.write_all(&frame)?;
+    connection.flush()
+}
+
+// The client broke the protocol. Passed around inside an io::Error, so pump() can tell.
+#[derive(Debug)]
+struct ProtocolError(&'static str);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WebSocket protocol error: {}", self.0)
+    }
+}
+
+impl Error for ProtocolError {}
+
+fn protocol_error(message: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, ProtocolError(message))
+}
+
+/* SHA-1, only used for the handshake. It's broken for security purposes, but here it's just
+a checksum the protocol asks for, so there's no need to pull in a crate for it.
+*/
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // Pad to a multiple of 64 bytes: a 1 bit, zeros, then the length in bits.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        // Every 3 bytes become 4 characters of 6 bits each. A short last chunk is padded with '='.
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        // The example handshake from RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn parses_masked_text_frame() {
+        // "Hello", masked, from RFC 6455 section 5.7
+        let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+        assert!(parse_frame(&frame[..6]).unwrap().is_none()); // Not all here yet
+
+        let (frame, used) = parse_frame(&frame).unwrap().unwrap();
+        assert_eq!(used, 11);
+        assert!(frame.fin);
+        assert_eq!(to_message(frame.opcode, frame.payload).unwrap(), Message::Text("Hello".to_string()));
+    }
+
+    // A client that sends `sends`, then nothing, and collects what's written to it.
+    struct Client {
+        sends: Vec<u8>,
+        got: Vec<u8>,
+    }
+
+    impl io::Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.sends.is_empty() {
+                thread::sleep(Duration::from_millis(5));
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.sends.len());
+            buf[..n].copy_from_slice(&self.sends[..n]);
+            self.sends.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl io::Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.got.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Client {
+        fn set_read_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Run a client that sends `sends` until the connection's closed, and return what it got.
+    fn exchange_with(sends: &[u8], idle: Duration) -> (io::Result<()>, Vec<u8>) {
+        let mut client = Client { sends: sends.to_vec(), got: Vec::new() };
+        let (to_handler, _incoming) = mpsc::channel();
+        let (_outgoing, from_handler) = mpsc::channel();
+
+        let result = pump(&mut client, &to_handler, &from_handler, idle);
+        (result, client.got)
+    }
+
+    #[test]
+    fn silent_clients_are_pinged_then_closed() {
+        let (result, got) = exchange_with(&[], Duration::from_millis(50));
+        result.unwrap();
+
+        // An empty ping, then a close with 1001 (going away).
+        let mut close = vec![0x88, 6, 0x03, 0xE9];
+        close.extend_from_slice(b"idle");
+        assert_eq!(got, [&[0x89, 0][..], &close].concat());
+    }
+
+    #[test]
+    fn protocol_errors_close_with_1002() {
+        let mask = [1, 2, 3, 4];
+        let frames = [
+            [&[0x09, 0x80][..], &mask].concat(), // The first half of a fragmented ping
+            [&[0xC1, 0x80][..], &mask].concat(), // A text frame with RSV1 set
+            [&[0x89, 0xFE, 0, 126][..], &mask, &[0; 126]].concat(), // A ping that's too long
+        ];
+
+        for frame in frames {
+            let (result, got) = exchange_with(&frame, IDLE);
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(got, [0x88, 2, 0x03, 0xEA]);
+        }
+    }
+
+    #[test]
+    fn only_so_many_sockets_can_be_open() {
+        static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+        let first = Slot::take(&OPEN, 2).unwrap();
+        let _second = Slot::take(&OPEN, 2).unwrap();
+        assert!(Slot::take(&OPEN, 2).is_none());
+
+        drop(first);
+        assert!(Slot::take(&OPEN, 2).is_some());
+        assert_eq!(OPEN.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,32 @@
+// Setup shared by the integration tests. Include it with `mod common;`
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use hello::{
+    access_log::{AccessLog, LogFormat},
+    app,
+    config::{Config, Mode},
+    testing::TestServer,
+};
+
+// The hello app, serving the HTML files in the crate's own directory, in the given mode.
+pub fn start_app(config: Config) -> TestServer {
+    let config = Config {
+        doc_root: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
+        ..config
+    };
+    // The tests don't look at the access log, so throw it away.
+    let access_log = AccessLog::new(LogFormat::Common, io::sink());
+
+    let pool = config.thread_pool();
+    let app = app::new(&config, access_log, &pool);
+
+    TestServer::start_with_pool(Arc::new(app), config, pool)
+}
+
+pub fn start_hello(mode: Mode) -> TestServer {
+    start_app(Config {
+        mode,
+        ..Config::default()
+    })
+}
@@ -0,0 +1,376 @@
+/* Regression tests for the server, run against real sockets.
+Use `cargo test --test server` to run just these.
+*/
+
+use std::{io::prelude::*, sync::Arc, thread, time::Duration};
+
+use flate2::read::GzDecoder;
+use hello::{
+    compress::Compress,
+    config::{Config, Mode},
+    http::{Request, Response},
+    middleware::Pipeline,
+    pool::Overflow,
+    testing::{TempDir, TestRequest, TestServer},
+};
+
+mod common;
+
+#[test]
+fn serves_the_home_page() {
+    for mode in [Mode::Threads, Mode::Events] {
+        let server = common::start_hello(mode);
+
+        server
+            .get("/")
+            .assert_status(200)
+            .assert_body_contains("Hi from Rust")
+            .assert_header("Access-Control-Allow-Origin", "*")
+            .assert_has_header("X-Request-Id")
+            .assert_has_header("X-Response-Time");
+    }
+}
+
+#[test]
+fn unknown_routes_get_404() {
+    for mode in [Mode::Threads, Mode::Events] {
+        let server = common::start_hello(mode);
+
+        server.get("/nope").assert_status(404).assert_body_contains("Oops!");
+        // Only GET is routed to the home page.
+        server.request("POST", "/").body("data").send().assert_status(404);
+    }
+}
+
+#[test]
+fn proxied_routes_reach_the_upstream() {
+    let upstream = TestServer::start(|request: &Request| Response::new(200).with_body(request.path.clone()));
+    let server = common::start_app(Config {
+        proxies: vec![("/api".to_string(), vec![upstream.address().to_string()])],
+        ..Config::default()
+    });
+
+    server.get("/api/users").assert_status(200).assert_body("/api/users");
+    server.get("/apiary").assert_status(404);
+}
+
+#[test]
+fn proxied_head_requests_keep_the_upstream_length() {
+    let css = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/static/style.css")).unwrap();
+    let upstream = common::start_hello(Mode::Threads);
+    let server = common::start_app(Config {
+        proxies: vec![("/static".to_string(), vec![upstream.address().to_string()])],
+        ..Config::default()
+    });
+
+    server
+        .request("HEAD", "/static/style.css")
+        .send()
+        .assert_status(200)
+        .assert_header("Content-Length", &css.len().to_string())
+        .assert_body("");
+    server.get("/static/style.css").assert_status(200).assert_body(&css);
+}
+
+#[test]
+fn shutdown_finishes_requests_in_progress() {
+    for mode in [Mode::Threads, Mode::Events] {
+        let slow = Arc::new(|_: &Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::new(200).with_body("done")
+        });
+        let server = TestServer::start_with(slow, Config { mode, ..Config::default() });
+        let address = server.address();
+
+        let request = thread::spawn(move || TestRequest::new(address, "GET", "/").send());
+        thread::sleep(Duration::from_millis(100)); // Let the request reach the handler.
+        server.shutdown();
+
+        request.join().unwrap().assert_status(200).assert_body("done");
+    }
+}
+
+#[test]
+fn nothing_is_served_after_shutdown() {
+    for mode in [Mode::Threads, Mode::Events] {
+        let app = Arc::new(|_: &Request| Response::new(200));
+        let server = TestServer::start_with(app, Config { mode, ..Config::default() });
+        let address = server.address();
+
+        server.get("/").assert_status(200);
+        server.shutdown();
+
+        let after = TestRequest::new(address, "GET", "/").try_send();
+        assert!(after.is_err(), "{mode:?} server still answered after shutdown");
+    }
+}
+
+#[test]
+fn full_pool_turns_connections_away() {
+    for mode in [Mode::Threads, Mode::Events] {
+        let slow = Arc::new(|_: &Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::new(200)
+        });
+        let config = Config {
+            mode,
+            workers: 1,
+            queue_size: 1,
+            overflow: Overflow::Reject,
+            ..Config::default()
+        };
+        let server = TestServer::start_with(slow, config);
+        let address = server.address();
+
+        // One request for the only worker, and one for the only place in the queue.
+        let accepted: Vec<_> = (0..2)
+            .map(|_| {
+                let request = thread::spawn(move || TestRequest::new(address, "GET", "/").send());
+                thread::sleep(Duration::from_millis(50));
+                request
+            })
+            .collect();
+
+        server.get("/").assert_status(503).assert_header("Retry-After", "1");
+        for request in accepted {
+            request.join().unwrap().assert_status(200);
+        }
+    }
+}
+
+#[test]
+fn metrics_report_requests_and_pool() {
+    let server = common::start_hello(Mode::Threads);
+
+    server.get("/");
+    server.get("/made-up");
+
+    server
+        .get("/metrics")
+        .assert_status(200)
+        .assert_body_contains("http_requests_total{route=\"/\",status=\"200\"} 1\n")
+        .assert_body_contains("http_requests_total{route=\"other\",status=\"404\"} 1\n")
+        .assert_body_contains("http_request_duration_seconds_count{route=\"/\"} 1\n")
+        .assert_body_contains("http_active_connections ")
+        // Exact worker counts would race with the earlier requests' workers finishing up.
+        .assert_body_contains("threadpool_workers{state=\"busy\"} ")
+        .assert_body_contains("threadpool_workers{state=\"idle\"} ")
+        .assert_body_contains("threadpool_queue_depth 0\n");
+}
+
+#[test]
+fn streams_and_compresses_responses() {
+    let server = common::start_app(Config::default());
+
+    // hello.html is too small to be worth compressing.
+    let small = server.request("GET", "/").header("Accept-Encoding", "gzip").send();
+    assert_eq!(small.assert_status(200).header("Content-Encoding"), None);
+
+    let page = |_: &Request| {
+        Response::new(200).with_stream(|writer| {
+            for i in 0..10_000 {
+                writeln!(writer, "line {i}")?;
+            }
+            Ok(())
+        })
+    };
+    let app = Pipeline::new(page).with(Compress::new());
+    let server = TestServer::start(app);
+
+    // The client doesn't decompress, so check the body is gzip, then unzip it.
+    let response = server.request("GET", "/").header("Accept-Encoding", "gzip").send();
+    response.assert_status(200).assert_header("Content-Encoding", "gzip");
+    let mut body = String::new();
+    GzDecoder::new(&response.response.body[..]).read_to_string(&mut body).unwrap();
+    assert!(body.starts_with("line 0\n") && body.ends_with("line 9999\n"));
+
+    server.get("/").assert_status(200).assert_body_contains("line 9999\n");
+}
+
+#[test]
+fn pages_are_rendered_from_templates() {
+    let server = common::start_hello(Mode::Threads);
+
+    // Whatever the client sends is escaped, so it can't add its own HTML.
+    server
+        .get("/<script>")
+        .assert_status(404)
+        .assert_body_contains("<title>Hello!</title>")
+        .assert_body_contains("<code>/&lt;script&gt;</code>");
+
+    server
+        .request("GET", "/info")
+        .header("X-Test", "a \"quoted\" value")
+        .send()
+        .assert_status(200)
+        .assert_header("Content-Type", "text/html; charset=utf-8")
+        .assert_body_contains("<code>GET /info HTTP/1.1</code>")
+        .assert_body_contains("<tr><th>X-Test</th><td>a &quot;quoted&quot; value</td></tr>");
+}
+
+#[test]
+fn virtual_hosts_have_their_own_doc_roots() {
+    let docs = TempDir::new("vhost");
+    std::fs::write(docs.join("hello.html"), "<h1>Docs</h1>").unwrap();
+
+    let server = common::start_app(Config {
+        vhosts: vec![("docs.localhost".to_string(), docs.path().to_path_buf())],
+        ..Config::default()
+    });
+
+    let get = |host: &str, path: &str| server.request("GET", path).header("Host", host).send();
+    get("docs.localhost:7878", "/").assert_status(200).assert_body("<h1>Docs</h1>");
+    get("other.localhost", "/").assert_status(200).assert_body_contains("Hi from Rust");
+    // The docs site has no 404.html of its own, so it gets a plain one.
+    get("docs.localhost", "/nope").assert_status(404).assert_body("404 Not Found\n");
+}
+
+#[test]
+fn static_files_support_ranges_and_caching() {
+    let css = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/static/style.css")).unwrap();
+
+    for mode in [Mode::Threads, Mode::Events] {
+        let server = common::start_hello(mode);
+
+        let whole = server.get("/static/style.css");
+        whole
+            .assert_status(200)
+            .assert_body(&css)
+            .assert_header("Accept-Ranges", "bytes")
+            .assert_header("Content-Type", "text/css; charset=utf-8");
+        let etag = whole.header("ETag").unwrap();
+        let last_modified = whole.header("Last-Modified").unwrap();
+
+        let css_file = |range: &str| server.request("GET", "/static/style.css").header("Range", range);
+        css_file("bytes=0-3")
+            .send()
+            .assert_status(206)
+            .assert_header("Content-Range", &format!("bytes 0-3/{}", css.len()))
+            .assert_body(&css[..4]);
+        css_file("bytes=0-3,-2")
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .assert_status(206)
+            .assert_body_contains(&format!("Content-Range: bytes {}-{}/{}", css.len() - 2, css.len() - 1, css.len()));
+        css_file("bytes=100000-").send().assert_status(416);
+
+        let conditional = |name: &str, value: &str| server.request("GET", "/static/style.css").header(name, value).send();
+        conditional("If-None-Match", etag).assert_status(304).assert_body("");
+        conditional("If-None-Match", "\"something else\"").assert_status(200);
+        conditional("If-Modified-Since", last_modified).assert_status(304);
+        conditional("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT").assert_status(200);
+
+        server
+            .request("HEAD", "/static/style.css")
+            .send()
+            .assert_status(200)
+            .assert_header("Content-Length", &css.len().to_string())
+            .assert_body("");
+        server.get("/static/../Cargo.toml").assert_status(404);
+    }
+}
+
+#[test]
+fn websockets_give_their_worker_back() {
+    let server = common::start_app(Config {
+        workers: 1,
+        ..Config::default()
+    });
+
+    let mut socket = std::net::TcpStream::connect(server.address()).unwrap();
+    socket
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let mut handshake = [0; 12];
+    socket.read_exact(&mut handshake).unwrap();
+    assert_eq!(&handshake, b"HTTP/1.1 101");
+
+    // The socket is still open, and the only worker is free for other requests.
+    server.get("/").assert_status(200);
+
+    // "Hi", masked with a zero key, comes back unmasked.
+    socket.write_all(&[0x81, 0x82, 0, 0, 0, 0, b'H', b'i']).unwrap();
+    let mut echo = Vec::new();
+    while !echo.ends_with(&[0x81, 2, b'H', b'i']) {
+        let mut byte = [0];
+        socket.read_exact(&mut byte).unwrap();
+        echo.push(byte[0]);
+    }
+}
+
+#[test]
+fn static_files_that_offer_ranges_are_not_compressed() {
+    let site = TempDir::new("ranges");
+    std::fs::create_dir(site.join("static")).unwrap();
+    let text: String = (0..2000).map(|i| format!("line {i}\n")).collect();
+    std::fs::write(site.join("static/big.txt"), &text).unwrap();
+
+    let server = common::start_app(Config {
+        vhosts: vec![("files.localhost".to_string(), site.path().to_path_buf())],
+        ..Config::default()
+    });
+    let big_txt = || {
+        server
+            .request("GET", "/static/big.txt")
+            .header("Host", "files.localhost")
+            .header("Accept-Encoding", "gzip")
+    };
+
+    // Sent as it is, so the ETag stays strong and the bytes are the ones ranges count.
+    let whole = big_txt().send();
+    whole.assert_status(200).assert_header("Accept-Ranges", "bytes").assert_body(&text);
+    assert_eq!(whole.header("Content-Encoding"), None);
+    let etag = whole.header("ETag").unwrap();
+    assert!(!etag.starts_with("W/"));
+
+    // So a download cut short can be picked up where it left off.
+    big_txt()
+        .header("Range", "bytes=100-")
+        .header("If-Range", etag)
+        .send()
+        .assert_status(206)
+        .assert_body(&text[100..]);
+    big_txt().header("Range", "bytes=100-199").send().assert_status(206).assert_body(&text[100..200]);
+
+    let head = server
+        .request("HEAD", "/static/big.txt")
+        .header("Host", "files.localhost")
+        .header("Accept-Encoding", "gzip")
+        .send();
+    head.assert_status(200)
+        .assert_header("ETag", etag)
+        .assert_header("Content-Length", &text.len().to_string());
+}
+
+#[test]
+fn requests_dropped_from_the_queue_get_503() {
+    let slow = Arc::new(|_: &Request| {
+        thread::sleep(Duration::from_millis(300));
+        Response::new(200)
+    });
+    let config = Config {
+        mode: Mode::Events,
+        workers: 1,
+        queue_size: 1,
+        overflow: Overflow::DropOldest,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(slow, config);
+    let address = server.address();
+
+    // The first is run, the second queued, and then pushed out of the queue by the third.
+    let requests: Vec<_> = (0..3)
+        .map(|_| {
+            let request = TestRequest::new(address, "GET", "/").timeout(Duration::from_secs(5));
+            let request = thread::spawn(move || request.send());
+            thread::sleep(Duration::from_millis(50));
+            request
+        })
+        .collect();
+
+    let statuses: Vec<u16> = requests.into_iter().map(|request| request.join().unwrap().status()).collect();
+    assert_eq!(statuses, [200, 503, 200]);
+}